@@ -0,0 +1,165 @@
+//! Where the hooks jump to. The entry code lives in memory that is never
+//! freed, so a thread that gets there at any time, even after the DLL has been
+//! unloaded, runs valid code. It counts itself in before any code of the DLL
+//! runs, then calls the detour, or the game's own function once the gate is
+//! closed, and counts itself out after.
+
+use island_protocol::MAX_FEATURES;
+use min_hook_rs::{HookError, Result};
+use std::ffi::c_void;
+use std::mem::{offset_of, size_of};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use windows_sys::Win32::System::Diagnostics::Debug::{
+    IMAGE_RUNTIME_FUNCTION_ENTRY, IMAGE_RUNTIME_FUNCTION_ENTRY_0, RtlAddFunctionTable,
+};
+use windows_sys::Win32::System::Memory::*;
+
+const PAGE_SIZE: usize = 0x1000;
+const ENTRY_SIZE: usize = 64;
+// The entry code up to and including `sub rsp, 0x28`; nothing before it
+// touches the stack
+const PROLOG_LEN: u8 = 21;
+
+// The first page; the entry code is on the second
+#[repr(C)]
+struct GateData {
+    // Threads in an entry or a detour it called
+    in_flight: AtomicU64,
+    // Nonzero while the detours may be called
+    open: AtomicU8,
+    detours: [AtomicUsize; MAX_FEATURES],
+    // The game's own function per entry, for callers once the gate is closed
+    originals: [AtomicUsize; MAX_FEATURES],
+    unwind_info: [u8; 8],
+    functions: [IMAGE_RUNTIME_FUNCTION_ENTRY; MAX_FEATURES],
+}
+
+const _: () = assert!(size_of::<GateData>() <= PAGE_SIZE);
+const _: () = assert!(MAX_FEATURES * ENTRY_SIZE <= PAGE_SIZE);
+
+static GATE: AtomicPtr<GateData> = AtomicPtr::new(ptr::null_mut());
+
+/// Allocate the gate, open. It is never freed.
+pub fn create() -> Result<()> {
+    unsafe {
+        let base = VirtualAlloc(
+            ptr::null(),
+            2 * PAGE_SIZE,
+            MEM_COMMIT | MEM_RESERVE,
+            PAGE_READWRITE,
+        ) as usize;
+        if base == 0 {
+            return Err(HookError::MemoryAlloc);
+        }
+        // Zeroed by VirtualAlloc, which is a valid `GateData`
+        let data = &mut *(base as *mut GateData);
+        let code = base + PAGE_SIZE;
+        for index in 0..MAX_FEATURES {
+            let entry = code + index * ENTRY_SIZE;
+            let bytes = entry_code(entry, data, index);
+            ptr::copy_nonoverlapping(bytes.as_ptr(), entry as *mut u8, bytes.len());
+            data.functions[index] = IMAGE_RUNTIME_FUNCTION_ENTRY {
+                BeginAddress: (entry - base) as u32,
+                EndAddress: (entry - base + bytes.len()) as u32,
+                Anonymous: IMAGE_RUNTIME_FUNCTION_ENTRY_0 {
+                    UnwindInfoAddress: offset_of!(GateData, unwind_info) as u32,
+                },
+            };
+        }
+        // Version 1 without handlers, one code: UWOP_ALLOC_SMALL of 0x28 at
+        // the end of the prolog
+        data.unwind_info = [1, PROLOG_LEN, 1, 0, PROLOG_LEN, 0x42, 0, 0];
+
+        let mut old = 0;
+        if VirtualProtect(
+            code as *const c_void,
+            PAGE_SIZE,
+            PAGE_EXECUTE_READ,
+            &mut old,
+        ) == 0
+        {
+            return Err(HookError::MemoryProtect);
+        }
+        // So exceptions thrown by the game unwind through a detour's entry
+        RtlAddFunctionTable(data.functions.as_ptr(), MAX_FEATURES as u32, base as u64);
+        data.open.store(1, Ordering::SeqCst);
+        GATE.store(data, Ordering::Release);
+    }
+    Ok(())
+}
+
+fn gate() -> Option<&'static GateData> {
+    unsafe { GATE.load(Ordering::Acquire).as_ref() }
+}
+
+/// The entry for `Feature` number `index`, calling `detour`: the address to hook with.
+pub fn entry(index: usize, detour: usize) -> Option<usize> {
+    let data = gate()?;
+    data.detours.get(index)?.store(detour, Ordering::Release);
+    Some(data as *const GateData as usize + PAGE_SIZE + index * ENTRY_SIZE)
+}
+
+/// Where the entry for `index` sends callers once the gate is closed. Must be
+/// set before the hook is enabled.
+pub fn set_original(index: usize, original: usize) {
+    if let Some(slot) = gate().and_then(|data| data.originals.get(index)) {
+        slot.store(original, Ordering::Release);
+    }
+}
+
+/// Send every caller from now on to the game's own functions.
+pub fn close() {
+    if let Some(data) = gate() {
+        data.open.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Threads in an entry or a detour it called. A thread counted in after the
+/// gate closed never reaches the DLL's code, so once this is zero after
+/// `close`, none will.
+pub fn in_flight() -> u64 {
+    gate().map_or(0, |data| data.in_flight.load(Ordering::SeqCst))
+}
+
+// The instructions of one entry
+fn entry_code(entry: usize, data: &GateData, index: usize) -> Vec<u8> {
+    let in_flight = &raw const data.in_flight as usize;
+    let open = &raw const data.open as usize;
+    let detour = &raw const data.detours[index] as usize;
+    let original = &raw const data.originals[index] as usize;
+
+    let mut code = Vec::with_capacity(ENTRY_SIZE);
+    // lock inc qword [in_flight]
+    rip_relative(&mut code, entry, &[0xF0, 0x48, 0xFF, 0x05], in_flight, &[]);
+    // cmp byte [open], 0
+    rip_relative(&mut code, entry, &[0x80, 0x3D], open, &[0]);
+    // je closed
+    code.extend_from_slice(&[0x74, 0]);
+    let branch = code.len();
+    // sub rsp, 0x28: shadow space, and the stack aligned again for the call
+    code.extend_from_slice(&[0x48, 0x83, 0xEC, 0x28]);
+    debug_assert_eq!(code.len(), PROLOG_LEN as usize);
+    // call [detour]
+    rip_relative(&mut code, entry, &[0xFF, 0x15], detour, &[]);
+    // add rsp, 0x28
+    code.extend_from_slice(&[0x48, 0x83, 0xC4, 0x28]);
+    // lock dec qword [in_flight]
+    rip_relative(&mut code, entry, &[0xF0, 0x48, 0xFF, 0x0D], in_flight, &[]);
+    // ret
+    code.push(0xC3);
+    // closed: lock dec qword [in_flight]; jmp [original]
+    code[branch - 1] = (code.len() - branch) as u8;
+    rip_relative(&mut code, entry, &[0xF0, 0x48, 0xFF, 0x0D], in_flight, &[]);
+    rip_relative(&mut code, entry, &[0xFF, 0x25], original, &[]);
+    debug_assert!(code.len() <= ENTRY_SIZE);
+    code
+}
+
+// Append an instruction addressing `target` relative to the next instruction
+fn rip_relative(code: &mut Vec<u8>, entry: usize, opcode: &[u8], target: usize, immediate: &[u8]) {
+    let next = entry + code.len() + opcode.len() + 4 + immediate.len();
+    code.extend_from_slice(opcode);
+    code.extend_from_slice(&((target as isize - next as isize) as i32).to_le_bytes());
+    code.extend_from_slice(immediate);
+}
@@ -1,11 +1,23 @@
 //! Acknowledgement: <https://github.com/DGP-Studio/UnlockerIsland>
 
+mod gate;
+
+use island_protocol::endpoints::OpenTeamAction;
+use island_protocol::hooks::{self, HookBackend};
+use island_protocol::{
+    CommandResult, FUNCTION_COUNT, Feature, FunctionOffsets, IslandEnvironment, IslandError,
+    IslandState, LogLevel, MAX_FEATURES, MODULE_NAME_LEN, ModuleOffset, PROTOCOL_VERSION, Settings,
+    SharedEnvironment, endpoints,
+};
 use min_hook_rs::*;
 use std::ffi::{CString, c_void};
 use std::mem;
 use std::ptr;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::time::Duration;
 use windows_sys::Win32::Foundation::*;
+use windows_sys::Win32::System::Diagnostics::Debug::ReadProcessMemory;
 use windows_sys::Win32::System::LibraryLoader::*;
 use windows_sys::Win32::System::Memory::*;
 use windows_sys::Win32::System::SystemServices::*;
@@ -44,90 +56,57 @@ type CraftEntryMethodPartner = unsafe extern "system" fn(
     *mut c_void,
 ) -> bool;
 
-// Environment structure
-#[repr(i32)]
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum IslandState {
-    None = 0,
-    Error = 1,
-    Started = 2,
-    Stopped = 3,
-}
+// Global state, null before the header is checked and again once teardown starts
+static P_ENVIRONMENT: AtomicPtr<IslandEnvironment> = AtomicPtr::new(ptr::null_mut());
 
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-struct FunctionOffsets {
-    find_string: u32,
-    set_field_of_view: u32,
-    set_enable_fog_rendering: u32,
-    set_target_frame_rate: u32,
-    open_team: u32,
-    open_team_page_accordingly: u32,
-    check_can_enter: u32,
-    craft_entry: u32,
-    craft_entry_partner: u32,
+// Address to call for each function, in `FunctionOffsets` order: the trampoline
+// of a hooked function, the resolved address of a helper, zero while unavailable
+static ADDRESSES: [AtomicUsize; FUNCTION_COUNT] = [const { AtomicUsize::new(0) }; FUNCTION_COUNT];
+
+// Hooked address per feature, for commands that act on installed hooks
+static HOOK_TARGETS: Mutex<[usize; MAX_FEATURES]> = Mutex::new([0; MAX_FEATURES]);
+
+// Last consistent settings snapshot, used while the launcher is mid-write
+static LAST_SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+
+// The function at offset key `name`, as its function pointer type `F`
+fn original<F: Copy>(name: &str) -> Option<F> {
+    let address = ADDRESSES[FunctionOffsets::index(name)?].load(Ordering::Acquire);
+    (address != 0).then(|| unsafe { mem::transmute_copy::<usize, F>(&address) })
 }
 
-#[repr(C)]
-#[derive(Debug)]
-struct IslandEnvironment {
-    state: IslandState,
-    last_error: u32,
-    function_offsets: FunctionOffsets,
-    field_of_view: f32,
-    fix_low_fov_scene: i32, // BOOL
-    disable_fog: i32,       // BOOL
-    target_frame_rate: i32,
-    remove_open_team_progress: i32, // BOOL
-    redirect_craft_entry: i32,      // BOOL
+// The environment for a detour. The gate counted the detour in before it
+// started, so the environment stays mapped until it returns.
+fn environment() -> Option<&'static IslandEnvironment> {
+    unsafe { P_ENVIRONMENT.load(Ordering::SeqCst).as_ref() }
 }
 
-// Original function pointers
-#[derive(Default)]
-struct OriginalFunctions {
-    find_string: Option<FindString>,
-    set_field_of_view: Option<SetFieldOfViewMethod>,
-    set_enable_fog_rendering: Option<SetEnableFogRenderingMethod>,
-    set_target_frame_rate: Option<SetTargetFrameRateMethod>,
-    open_team: Option<OpenTeamMethod>,
-    open_team_page_accordingly: Option<OpenTeamPageAccordinglyMethod>,
-    check_can_enter: Option<CheckCanEnterMethod>,
-    craft_entry: Option<CraftEntryMethod>,
-    craft_entry_partner: Option<CraftEntryMethodPartner>,
+// Read the settings as a whole, never a half-applied change
+fn current_settings(env: &IslandEnvironment) -> Settings {
+    let mut last = LAST_SETTINGS.lock().unwrap();
+    if let Some((_, settings)) = env.settings.read() {
+        *last = Some(settings);
+    }
+    last.unwrap_or_default()
 }
 
-// Global state
-static ISLAND_ENVIRONMENT_NAME: &str = "4F3E8543-40F7-4808-82DC-21E48A6037A7";
-static mut P_ENVIRONMENT: *mut IslandEnvironment = ptr::null_mut();
-static ORIGINALS: Mutex<OriginalFunctions> = Mutex::new(OriginalFunctions {
-    find_string: None,
-    set_field_of_view: None,
-    set_enable_fog_rendering: None,
-    set_target_frame_rate: None,
-    open_team: None,
-    open_team_page_accordingly: None,
-    check_can_enter: None,
-    craft_entry: None,
-    craft_entry_partner: None,
-});
+const HEARTBEAT_INTERVAL_MS: u32 = 1000;
+const COMMAND_POLL_INTERVAL_MS: u32 = 100;
+const DRAIN_POLL_INTERVAL_MS: u32 = 10;
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(1);
 
 // Memory protection disabling
-unsafe extern "system" {
-    unsafe fn LdrAddRefDll(flags: u32, dll_handle: *mut c_void) -> i32;
-}
-
-const LDR_ADDREF_DLL_PIN: u32 = 0x00000001;
 
 fn disable_protect_virtual_memory() {
     unsafe {
-        let ntdll = GetModuleHandleA(b"ntdll.dll\0".as_ptr());
+        let ntdll = GetModuleHandleA(c"ntdll.dll".as_ptr().cast());
         if ntdll.is_null() {
             return;
         }
 
         let p_nt_protect_virtual_memory =
-            GetProcAddress(ntdll, b"NtProtectVirtualMemory\0".as_ptr());
-        let p_nt_query_section = GetProcAddress(ntdll, b"NtQuerySection\0".as_ptr());
+            GetProcAddress(ntdll, c"NtProtectVirtualMemory".as_ptr().cast());
+        let p_nt_query_section = GetProcAddress(ntdll, c"NtQuerySection".as_ptr().cast());
 
         if let (Some(protect_fn), Some(query_fn)) =
             (p_nt_protect_virtual_memory, p_nt_query_section)
@@ -153,222 +132,341 @@ fn disable_protect_virtual_memory() {
 
 // FOV endpoint handler
 unsafe extern "system" fn set_field_of_view_endpoint(p_this: *mut c_void, value: f32) {
+    let Some(env) = environment() else {
+        return;
+    };
     unsafe {
-        if P_ENVIRONMENT.is_null() {
-            return;
-        }
+        let settings = current_settings(env);
 
-        let env = &*P_ENVIRONMENT;
+        let frame_rate_fn = original::<SetTargetFrameRateMethod>("set_target_frame_rate");
+        let fov_fn = original::<SetFieldOfViewMethod>("set_field_of_view");
+        let fog_fn = original::<SetEnableFogRenderingMethod>("set_enable_fog_rendering");
 
-        let (frame_rate_fn, fov_fn, fog_fn) = {
-            let originals = ORIGINALS.lock().unwrap();
-            (
-                originals.set_target_frame_rate,
-                originals.set_field_of_view,
-                originals.set_enable_fog_rendering,
-            )
-        };
-
-        // Set target frame rate
+        let calls = endpoints::field_of_view(&settings, value);
         if let Some(frame_rate_fn) = frame_rate_fn {
-            frame_rate_fn(env.target_frame_rate);
+            frame_rate_fn(calls.target_frame_rate);
         }
+        if let Some(fog_fn) = fog_fn {
+            fog_fn(calls.enable_fog);
+        }
+        if let Some(fov_fn) = fov_fn {
+            fov_fn(p_this, calls.field_of_view);
+        }
+        env.effective.write(&calls.effective(value));
+    }
+}
 
-        // Handle FOV and fog based on current view value
-        if value.floor() <= 30.0 {
-            // Low FOV scene
-            if let Some(fog_fn) = fog_fn {
-                fog_fn(false); // Always disable fog in low FOV scenes
-            }
-            if let Some(fov_fn) = fov_fn {
-                let fov_value = if env.fix_low_fov_scene != 0 {
-                    env.field_of_view // Use user-defined FOV
-                } else {
-                    value // Keep original low FOV
-                };
-                fov_fn(p_this, fov_value);
-            }
-        } else {
-            // Normal FOV scene
-            if let Some(fog_fn) = fog_fn {
-                fog_fn(env.disable_fog == 0); // Respect user fog setting
+unsafe extern "system" fn open_team_endpoint() {
+    let Some(env) = environment() else {
+        return;
+    };
+    unsafe {
+        let settings = current_settings(env);
+
+        let check_fn = original::<CheckCanEnterMethod>("check_can_enter");
+        let page_fn = original::<OpenTeamPageAccordinglyMethod>("open_team_page_accordingly");
+        let team_fn = original::<OpenTeamMethod>("open_team");
+
+        match endpoints::open_team(&settings, || check_fn.is_some_and(|f| f())) {
+            OpenTeamAction::OpenTeamPage => {
+                if let Some(page_fn) = page_fn {
+                    page_fn(false);
+                }
             }
-            if let Some(fov_fn) = fov_fn {
-                fov_fn(p_this, env.field_of_view); // Use user-defined FOV
+            OpenTeamAction::OpenTeam => {
+                if let Some(team_fn) = team_fn {
+                    team_fn();
+                }
             }
         }
     }
 }
 
-unsafe extern "system" fn open_team_endpoint() {
+unsafe extern "system" fn craft_entry_endpoint(p_this: *mut c_void) {
+    let Some(env) = environment() else {
+        return;
+    };
     unsafe {
-        if P_ENVIRONMENT.is_null() {
+        let settings = current_settings(env);
+
+        let partner_fn = original::<FindString>("find_string");
+        let craft_partner_fn = original::<CraftEntryMethodPartner>("craft_entry_partner");
+        let craft_fn = original::<CraftEntryMethod>("craft_entry");
+
+        if endpoints::redirect_craft_entry(&settings)
+            && let (Some(partner_fn), Some(craft_partner_fn)) = (partner_fn, craft_partner_fn)
+        {
+            let synthesis_page = CString::new("SynthesisPage").unwrap();
+            let page_string = partner_fn(synthesis_page.as_ptr());
+            craft_partner_fn(
+                page_string,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
             return;
         }
 
-        let env = &*P_ENVIRONMENT;
-
-        let (check_fn, page_fn, team_fn) = {
-            let originals = ORIGINALS.lock().unwrap();
-            (
-                originals.check_can_enter,
-                originals.open_team_page_accordingly,
-                originals.open_team,
-            )
-        };
+        if let Some(craft_fn) = craft_fn {
+            craft_fn(p_this);
+        }
+    }
+}
 
-        let should_use_page_fn =
-            env.remove_open_team_progress != 0 && check_fn.is_some_and(|f| f());
+// The detour of each feature. What it hooks, the helpers it calls and the
+// settings it reads are declared in the feature's `FeatureSpec`.
+struct Hook {
+    feature: Feature,
+    detour: *mut c_void,
+}
 
-        if should_use_page_fn {
-            if let Some(page_fn) = page_fn {
-                page_fn(false);
+const HOOKS: [Hook; 3] = [
+    Hook {
+        feature: Feature::FieldOfView,
+        detour: set_field_of_view_endpoint as *mut c_void,
+    },
+    Hook {
+        feature: Feature::TeamProgress,
+        detour: open_team_endpoint as *mut c_void,
+    },
+    Hook {
+        feature: Feature::CraftRedirect,
+        detour: craft_entry_endpoint as *mut c_void,
+    },
+];
+
+const _: () = assert!(HOOKS.len() == Feature::ALL.len());
+
+// Resolve an offset to an address, treating zero as "not available"
+fn resolve_offset(env: &IslandEnvironment, offset: ModuleOffset) -> Option<*mut c_void> {
+    if offset.rva == 0 {
+        return None;
+    }
+    let base = unsafe {
+        match offset.module as usize {
+            0 => GetModuleHandleA(ptr::null()),
+            index => {
+                let name = env.modules.get(index)?;
+                if name[MODULE_NAME_LEN - 1] != 0 {
+                    return None;
+                }
+                GetModuleHandleA(name.as_ptr())
             }
-        } else if let Some(team_fn) = team_fn {
-            team_fn();
         }
+    };
+    if base.is_null() {
+        return None;
     }
+    Some((base as u64).checked_add(offset.rva)? as *mut c_void)
 }
 
-unsafe extern "system" fn craft_entry_endpoint(p_this: *mut c_void) {
-    unsafe {
-        if P_ENVIRONMENT.is_null() {
-            return;
+// Install MinHooks
+fn install_min_hooks(env: &IslandEnvironment) -> Result<()> {
+    for name in FunctionOffsets::FIELD_NAMES {
+        if let Some(offset) = env.function_offsets.get(name).filter(|o| o.rva != 0) {
+            env.log.write(
+                LogLevel::Debug,
+                &format!("{name} at {}", hooks::describe_offset(env, offset)),
+            );
         }
+    }
+    initialize()
+        .and_then(|()| gate::create())
+        .inspect_err(|e| {
+            env.log.write(
+                LogLevel::Error,
+                &format!("MinHook initialize failed: {}", e.as_str()),
+            );
+        })?;
+    hooks::sync_hooks(env, &mut MinHookBackend, &current_settings(env), true);
+    Ok(())
+}
 
-        let env = &*P_ENVIRONMENT;
+// MinHook, hooking the game in this process
+struct MinHookBackend;
 
-        let (partner_fn, craft_partner_fn, craft_fn) = {
-            let originals = ORIGINALS.lock().unwrap();
-            (
-                originals.find_string,
-                originals.craft_entry_partner,
-                originals.craft_entry,
+impl HookBackend for MinHookBackend {
+    fn resolve(&self, env: &IslandEnvironment, offset: ModuleOffset) -> Option<usize> {
+        resolve_offset(env, offset).map(|address| address as usize)
+    }
+
+    fn read_code(&self, address: usize, code: &mut [u8]) -> bool {
+        let mut read = 0;
+        // Unlike a plain read, this fails cleanly where a bad offset points at nothing
+        let ok = unsafe {
+            ReadProcessMemory(
+                GetCurrentProcess(),
+                address as *const c_void,
+                code.as_mut_ptr() as *mut c_void,
+                code.len(),
+                &mut read,
             )
         };
+        ok != 0 && read == code.len()
+    }
 
-        if env.redirect_craft_entry != 0 {
-            if let (Some(partner_fn), Some(craft_partner_fn)) = (partner_fn, craft_partner_fn) {
-                let synthesis_page = CString::new("SynthesisPage").unwrap();
-                let page_string = partner_fn(synthesis_page.as_ptr());
-                craft_partner_fn(
-                    page_string,
-                    ptr::null_mut(),
-                    ptr::null_mut(),
-                    ptr::null_mut(),
-                    ptr::null_mut(),
-                );
-                return;
+    fn create(
+        &mut self,
+        env: &IslandEnvironment,
+        feature: Feature,
+        target: usize,
+    ) -> std::result::Result<(), i32> {
+        let spec = feature.spec();
+        let hook = HOOKS.iter().find(|hook| hook.feature == feature).unwrap();
+        for &helper in spec.helpers {
+            let address = env
+                .function_offsets
+                .get(helper)
+                .and_then(|offset| resolve_offset(env, offset));
+            if let Some(index) = FunctionOffsets::index(helper) {
+                ADDRESSES[index].store(address.map_or(0, |p| p as usize), Ordering::Release);
             }
         }
+        // The protocol checks at compile time that every hooked key exists
+        let slot = &ADDRESSES[FunctionOffsets::index(spec.hooked).unwrap()];
+        let target = target as *mut c_void;
+        let entry = gate::entry(feature as usize, hook.detour as usize)
+            .ok_or(HookError::NotInitialized as i32)?;
+        create_hook(target, entry as *mut c_void)
+            .and_then(|trampoline| {
+                // The detour may run as soon as the hook is enabled
+                gate::set_original(feature as usize, trampoline as usize);
+                slot.store(trampoline as usize, Ordering::Release);
+                enable_hook(target).inspect_err(|_| {
+                    slot.store(0, Ordering::Release);
+                    let _ = remove_hook(target);
+                })
+            })
+            .map_err(|e| e as i32)?;
+        HOOK_TARGETS.lock().unwrap()[feature as usize] = target as usize;
+        Ok(())
+    }
 
-        if let Some(craft_fn) = craft_fn {
-            craft_fn(p_this);
-        }
+    fn created(&self, feature: Feature) -> bool {
+        HOOK_TARGETS.lock().unwrap()[feature as usize] != 0
     }
-}
 
-// Install MinHooks
-fn install_min_hooks(base: u64, env: &IslandEnvironment) -> Result<()> {
-    unsafe {
-        initialize()?;
-
-        let mut originals = ORIGINALS.lock().unwrap();
-
-        // Store original function pointers
-        originals.find_string = Some(mem::transmute::<*mut c_void, FindString>(
-            (base + env.function_offsets.find_string as u64) as *mut c_void,
-        ));
-        originals.set_enable_fog_rendering =
-            Some(mem::transmute::<*mut c_void, SetEnableFogRenderingMethod>(
-                (base + env.function_offsets.set_enable_fog_rendering as u64) as *mut c_void,
-            ));
-        originals.set_target_frame_rate =
-            Some(mem::transmute::<*mut c_void, SetTargetFrameRateMethod>(
-                (base + env.function_offsets.set_target_frame_rate as u64) as *mut c_void,
-            ));
-        originals.open_team_page_accordingly = Some(mem::transmute::<
-            *mut c_void,
-            OpenTeamPageAccordinglyMethod,
-        >(
-            (base + env.function_offsets.open_team_page_accordingly as u64) as *mut c_void,
-        ));
-        originals.check_can_enter = Some(mem::transmute::<*mut c_void, CheckCanEnterMethod>(
-            (base + env.function_offsets.check_can_enter as u64) as *mut c_void,
-        ));
-        originals.craft_entry_partner =
-            Some(mem::transmute::<*mut c_void, CraftEntryMethodPartner>(
-                (base + env.function_offsets.craft_entry_partner as u64) as *mut c_void,
-            ));
-
-        // Create hooks
-        let target = (base + env.function_offsets.set_field_of_view as u64) as *mut c_void;
-        let trampoline = create_hook(target, set_field_of_view_endpoint as *mut c_void)?;
-        originals.set_field_of_view = Some(mem::transmute::<*mut c_void, SetFieldOfViewMethod>(
-            trampoline,
-        ));
-
-        let target = (base + env.function_offsets.open_team as u64) as *mut c_void;
-        let trampoline = create_hook(target, open_team_endpoint as *mut c_void)?;
-        originals.open_team = Some(mem::transmute::<*mut c_void, OpenTeamMethod>(trampoline));
-
-        let target = (base + env.function_offsets.craft_entry as u64) as *mut c_void;
-        let trampoline = create_hook(target, craft_entry_endpoint as *mut c_void)?;
-        originals.craft_entry = Some(mem::transmute::<*mut c_void, CraftEntryMethod>(trampoline));
-
-        // Enable all hooks
-        enable_hook(ALL_HOOKS)?;
+    fn switch(&mut self, feature: Feature, on: bool) -> std::result::Result<(), i32> {
+        let target = HOOK_TARGETS.lock().unwrap()[feature as usize] as *mut c_void;
+        let result = if on {
+            enable_hook(target)
+        } else {
+            disable_hook(target)
+        };
+        result.map_err(|e| e as i32)
+    }
 
-        Ok(())
+    fn error_name(&self, code: i32) -> &'static str {
+        HookError::from_code(code).as_str()
     }
 }
 
-// Main DLL thread
+// Main DLL thread. However it ends, it drops the reference DllMain took,
+// which unloads the DLL.
 extern "system" fn island_thread(lp_param: *mut c_void) -> u32 {
+    let code = run_island();
+    unsafe { FreeLibraryAndExitThread(lp_param as HMODULE, code) }
+}
+
+// Check the header, hook and serve the launcher until it stops or unloads us.
+// Returns the thread exit code.
+fn run_island() -> u32 {
     unsafe {
-        let env_name_c = CString::new(ISLAND_ENVIRONMENT_NAME).unwrap();
+        // The launcher names the mapping after the game process; in any other
+        // process that loads the DLL, such as the launcher itself, there is none
+        let island = match SharedEnvironment::open(GetCurrentProcessId()) {
+            Ok(island) => island,
+            Err(_) => return GetLastError(),
+        };
 
-        let h_file = OpenFileMappingA(
-            FILE_MAP_READ | FILE_MAP_WRITE,
-            FALSE,
-            env_name_c.as_ptr() as *const u8,
-        );
-        if h_file.is_null() || h_file == INVALID_HANDLE_VALUE {
-            return GetLastError();
+        // Only the header is known to be there until it has been checked
+        let env = island.as_ptr();
+        let header = &mut (*env).header;
+        if let Err(error) = header.validate() {
+            header.error = error as u32;
+            header.state = IslandState::Error as u32;
+            header.dll_version = PROTOCOL_VERSION;
+            return error as u32;
         }
+        header.state = IslandState::Started as u32;
+        header.dll_version = PROTOCOL_VERSION;
+        P_ENVIRONMENT.store(env, Ordering::SeqCst);
+        (*env).log.write(
+            LogLevel::Info,
+            &format!("hutao_minhook attached, protocol {PROTOCOL_VERSION}"),
+        );
 
-        let lp_view = MapViewOfFile(h_file, FILE_MAP_READ | FILE_MAP_WRITE, 0, 0, 0);
-        if lp_view.Value.is_null() {
-            CloseHandle(h_file);
-            return GetLastError();
+        // Install hooks. Without MinHook or the gate, none was created.
+        if let Err(e) = install_min_hooks(&*env) {
+            P_ENVIRONMENT.store(ptr::null_mut(), Ordering::SeqCst);
+            let header = &mut (*env).header;
+            header.error = IslandError::HookInstallFailed as u32;
+            header.last_error = e as u32;
+            header.state = IslandState::Error as u32;
+            return e as u32;
         }
 
-        P_ENVIRONMENT = lp_view.Value as *mut IslandEnvironment;
-        (*P_ENVIRONMENT).state = IslandState::Started;
-
-        let base = GetModuleHandleA(ptr::null()) as u64;
-
-        // Install hooks
-        if install_min_hooks(base, &(*P_ENVIRONMENT)).is_err() {
-            (*P_ENVIRONMENT).state = IslandState::Error;
-            (*P_ENVIRONMENT).last_error = GetLastError();
-            UnmapViewOfFile(lp_view);
-            CloseHandle(h_file);
-            return GetLastError();
+        // Serve commands until the launcher stops or unloads us
+        let env_ptr = env;
+        let env = &*env_ptr;
+        let mut settings_generation = env.settings.read().map(|(generation, _)| generation);
+        let mut ticks = 0u32;
+        // Unload is acknowledged once it has happened, not when it is read
+        let mut unload = None;
+        let mut settling = false;
+        while ptr::read_volatile(&raw const env.header.state) != IslandState::Stopped as u32 {
+            unload = hooks::serve_commands(env, &mut MinHookBackend, &current_settings(env));
+            if unload.is_some() {
+                break;
+            }
+            // Hook or unhook features as the launcher changes the settings
+            let generation = env.settings.read().map(|(generation, _)| generation);
+            let changed = generation.is_some() && generation != settings_generation;
+            if changed || settling {
+                settings_generation = generation;
+                settling =
+                    hooks::sync_hooks(env, &mut MinHookBackend, &current_settings(env), false);
+            }
+            if ticks.is_multiple_of(HEARTBEAT_INTERVAL_MS / COMMAND_POLL_INTERVAL_MS) {
+                env.hook_report.heartbeat.fetch_add(1, Ordering::Release);
+            }
+            ticks = ticks.wrapping_add(1);
+            Sleep(COMMAND_POLL_INTERVAL_MS);
         }
 
-        // Wait indefinitely
-        WaitForSingleObject(GetCurrentThread(), u32::MAX);
-
         // Cleanup
+        env.log
+            .write(LogLevel::Info, "Removing hooks and unloading");
+        // A launcher that exits has published the defaults; let the game get them
+        hooks::settle_hooks(
+            env,
+            &mut MinHookBackend,
+            &current_settings(env),
+            SETTLE_TIMEOUT,
+            || Sleep(DRAIN_POLL_INTERVAL_MS),
+        );
+        // Hooks off, then the gate closed: threads still on their way to an
+        // entry go to the game's own function from here on, and once the
+        // count drops to zero no thread is in any code of the DLL
         let _ = disable_hook(ALL_HOOKS);
-        let _ = uninitialize();
-
-        (*P_ENVIRONMENT).state = IslandState::Stopped;
-        UnmapViewOfFile(lp_view);
-        CloseHandle(h_file);
+        gate::close();
+        while gate::in_flight() != 0 {
+            Sleep(DRAIN_POLL_INTERVAL_MS);
+        }
+        P_ENVIRONMENT.store(ptr::null_mut(), Ordering::SeqCst);
+        // MinHook is not uninitialized: its relays and trampolines stay
+        // allocated like the gate, for threads still passing through them
 
-        FreeLibraryAndExitThread(lp_param as HMODULE, 0);
+        ptr::write_volatile(
+            &raw mut (*env_ptr).header.state,
+            IslandState::Stopped as u32,
+        );
+        if let Some(slot) = unload {
+            env.commands.complete(slot, CommandResult::Ok, 0);
+        }
+        drop(island);
+        0
     }
 }
 
@@ -382,6 +480,10 @@ extern "system" fn island_get_window_hook_impl(
 }
 
 // Export functions
+
+/// # Safety
+///
+/// `p_hook_proc` must be valid for a pointer-sized write.
 #[unsafe(no_mangle)]
 pub unsafe extern "system" fn DllGetWindowsHookForHutao(p_hook_proc: *mut *mut c_void) -> HRESULT {
     unsafe {
@@ -391,6 +493,10 @@ pub unsafe extern "system" fn DllGetWindowsHookForHutao(p_hook_proc: *mut *mut c
 }
 
 // DLL entry point
+
+/// # Safety
+///
+/// Only the Windows loader may call this, with the handle of this module.
 #[unsafe(no_mangle)]
 pub unsafe extern "system" fn DllMain(
     h_module: HINSTANCE,
@@ -401,13 +507,24 @@ pub unsafe extern "system" fn DllMain(
         match ul_reason_for_call {
             DLL_PROCESS_ATTACH => {
                 DisableThreadLibraryCalls(h_module);
-                LdrAddRefDll(LDR_ADDREF_DLL_PIN, h_module);
+                // Keep the DLL loaded after the launcher removes the window hook
+                // that brought it in. Unlike a pin, the island thread can drop
+                // this reference again to unload.
+                let mut module: HMODULE = ptr::null_mut();
+                if GetModuleHandleExW(
+                    GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+                    DllMain as *const u16,
+                    &mut module,
+                ) == 0
+                {
+                    return FALSE;
+                }
                 disable_protect_virtual_memory();
                 CreateThread(
                     ptr::null_mut(),
                     0,
                     Some(island_thread),
-                    h_module,
+                    module,
                     0,
                     ptr::null_mut(),
                 );
@@ -0,0 +1,97 @@
+//! What the hooked game functions do with the settings. The DLL's detours make
+//! the game calls these describe; the simulator runs them without a game.
+
+use crate::{EffectiveValues, IslandEnvironment, Settings};
+
+/// Scenes at or below this field of view are close-ups such as dialogue.
+pub const LOW_FOV_SCENE: f32 = 30.0;
+
+/// Game calls made in place of `Camera.set_fieldOfView(value)`, in this order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldOfViewCalls {
+    pub target_frame_rate: i32,
+    pub enable_fog: bool,
+    pub field_of_view: f32,
+}
+
+impl FieldOfViewCalls {
+    /// What to write back after making these calls for the game's `value`.
+    pub fn effective(&self, value: f32) -> EffectiveValues {
+        EffectiveValues {
+            original_field_of_view: value,
+            field_of_view: self.field_of_view,
+            enable_fog: self.enable_fog,
+            target_frame_rate: self.target_frame_rate,
+            low_fov_scene: is_low_fov_scene(value),
+        }
+    }
+}
+
+pub fn is_low_fov_scene(value: f32) -> bool {
+    value.floor() <= LOW_FOV_SCENE
+}
+
+/// Whether the last call to the field of view detour, if any, set what the
+/// game's own settings would. Unity keeps the frame rate and fog it was last
+/// given, so until then unhooking would leave the launcher's values in place.
+pub fn field_of_view_restored(env: &IslandEnvironment) -> bool {
+    match env.effective.read() {
+        Some((0, _)) => true,
+        Some((_, effective)) => {
+            let value = effective.original_field_of_view;
+            field_of_view(&Settings::default(), value).effective(value) == effective
+        }
+        // Mid-write; look again later
+        None => false,
+    }
+}
+
+/// For features whose detour leaves nothing behind in the game.
+pub fn nothing_to_restore(_env: &IslandEnvironment) -> bool {
+    true
+}
+
+pub fn field_of_view(settings: &Settings, value: f32) -> FieldOfViewCalls {
+    if is_low_fov_scene(value) {
+        FieldOfViewCalls {
+            target_frame_rate: settings.target_frame_rate,
+            // Always disable fog in low FOV scenes
+            enable_fog: false,
+            field_of_view: if settings.fix_low_fov_scene {
+                settings.field_of_view
+            } else {
+                value
+            },
+        }
+    } else {
+        FieldOfViewCalls {
+            target_frame_rate: settings.target_frame_rate,
+            enable_fog: !settings.disable_fog,
+            field_of_view: settings.field_of_view,
+        }
+    }
+}
+
+/// What opening the team screen does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenTeamAction {
+    /// The original `OpenTeam`, with its progress animation.
+    OpenTeam,
+    /// `OpenTeamPageAccordingly(false)`, straight to the page.
+    OpenTeamPage,
+}
+
+/// `can_enter` asks the game whether the page may be opened directly; it is
+/// only called when the setting is on.
+pub fn open_team(settings: &Settings, can_enter: impl FnOnce() -> bool) -> OpenTeamAction {
+    if settings.remove_open_team_progress && can_enter() {
+        OpenTeamAction::OpenTeamPage
+    } else {
+        OpenTeamAction::OpenTeam
+    }
+}
+
+/// Whether opening the crafting table opens the synthesis page instead.
+pub fn redirect_craft_entry(settings: &Settings) -> bool {
+    settings.redirect_craft_entry
+}
@@ -0,0 +1,342 @@
+//! When each feature's hook goes in, is switched on and off, and what the
+//! launcher's commands do to the hooks. The DLL puts hooks in place with
+//! MinHook through `HookBackend`; the simulator only pretends to.
+
+use crate::{
+    Command, CommandResult, Feature, HookStatus, IslandEnvironment, LogLevel, MODULE_NAME_LEN,
+    ModuleOffset, PROLOGUE_LEN, PROLOGUE_MISMATCH, Prologue, Settings,
+};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// Puts hooks in place. Errors are MinHook status codes, the values reported
+/// in `HookReport::minhook_status`.
+pub trait HookBackend {
+    /// Address of a function in this process, or `None` if `offset` does not
+    /// point anywhere usable.
+    fn resolve(&self, env: &IslandEnvironment, offset: ModuleOffset) -> Option<usize>;
+
+    /// Fill `code` with the bytes at `address`, or return false if they cannot be read.
+    fn read_code(&self, address: usize, code: &mut [u8]) -> bool;
+
+    /// Hook `target`, the hooked function of `feature`, and enable the hook.
+    /// Every function of the feature has passed its prologue check by then.
+    fn create(
+        &mut self,
+        env: &IslandEnvironment,
+        feature: Feature,
+        target: usize,
+    ) -> Result<(), i32>;
+
+    /// Whether `create` has succeeded for `feature`. A created hook is only
+    /// switched from then on, so a detour still running returns through a
+    /// valid trampoline.
+    fn created(&self, feature: Feature) -> bool;
+
+    /// Enable or disable the created hook of `feature`.
+    fn switch(&mut self, feature: Feature, on: bool) -> Result<(), i32>;
+
+    /// Text for a status code returned by the other methods.
+    fn error_name(&self, code: i32) -> &'static str;
+}
+
+/// Where an offset points, for log records.
+pub fn describe_offset(env: &IslandEnvironment, offset: ModuleOffset) -> String {
+    let module = match offset.module as usize {
+        0 => "game".to_string(),
+        index => match env.modules.get(index) {
+            Some(name) => {
+                let len = name.iter().position(|&b| b == 0).unwrap_or(MODULE_NAME_LEN);
+                String::from_utf8_lossy(&name[..len]).into_owned()
+            }
+            None => format!("module #{index}"),
+        },
+    };
+    format!("{module}+{:#x}", offset.rva)
+}
+
+/// Bring every hook in line with `settings`: in place while its feature has
+/// something to do, off otherwise. `retry` also retries features that were
+/// skipped or failed, for Re-resolve Offsets. Hooks the launcher disabled stay off.
+///
+/// Returns true while a hook with nothing to do waits for its detour to put
+/// the game's own values back; call again until it returns false.
+pub fn sync_hooks(
+    env: &IslandEnvironment,
+    backend: &mut impl HookBackend,
+    settings: &Settings,
+    retry: bool,
+) -> bool {
+    let mut settling = false;
+    for feature in Feature::ALL {
+        let spec = feature.spec();
+        let active = spec.active(settings);
+        let status = env.hook_report.status(feature);
+        match status {
+            HookStatus::Installed if active => {}
+            HookStatus::Installed if !(spec.restored)(env) => settling = true,
+            HookStatus::Installed => switch_hook(env, backend, feature, false),
+            HookStatus::Disabled => {}
+            HookStatus::Skipped | HookStatus::Failed if !retry => {}
+            // Created before; only switch it
+            _ if backend.created(feature) => {
+                if active {
+                    switch_hook(env, backend, feature, true);
+                } else if status != HookStatus::Inactive {
+                    env.hook_report.mark(feature, HookStatus::Inactive, 0);
+                }
+            }
+            _ => install_hook(env, backend, feature, active),
+        }
+    }
+    settling
+}
+
+/// Before unhooking everything: give hooks left with nothing to do by
+/// `settings` up to `timeout` to put the game's own values back. `wait` runs
+/// between checks, while the game gets to call the detours.
+pub fn settle_hooks(
+    env: &IslandEnvironment,
+    backend: &mut impl HookBackend,
+    settings: &Settings,
+    timeout: Duration,
+    mut wait: impl FnMut(),
+) {
+    let deadline = Instant::now() + timeout;
+    while sync_hooks(env, backend, settings, false) {
+        if Instant::now() >= deadline {
+            env.log.write(
+                LogLevel::Warn,
+                "Unhooking before the game got its own settings back",
+            );
+            return;
+        }
+        wait();
+    }
+}
+
+// Create and enable the hook of one feature, reporting the outcome to the
+// launcher. A feature with nothing to do is only checked for an offset.
+fn install_hook(
+    env: &IslandEnvironment,
+    backend: &mut impl HookBackend,
+    feature: Feature,
+    active: bool,
+) {
+    let report = &env.hook_report;
+    let offset = env
+        .function_offsets
+        .get(feature.spec().hooked)
+        .unwrap_or_default();
+    let Some(target) = backend.resolve(env, offset) else {
+        report.mark(feature, HookStatus::Skipped, 0);
+        env.log.write(
+            LogLevel::Warn,
+            &format!(
+                "{}: skipped, no usable offset ({})",
+                feature.key(),
+                describe_offset(env, offset)
+            ),
+        );
+        return;
+    };
+    if !active {
+        if report.status(feature) != HookStatus::Inactive {
+            report.mark(feature, HookStatus::Inactive, 0);
+            env.log.write(
+                LogLevel::Info,
+                &format!("{}: off in the settings, not hooked", feature.key()),
+            );
+        }
+        return;
+    }
+
+    // Offsets that are wrong for this build would have MinHook patch, and the
+    // detour call, whatever code is there
+    for name in feature.offset_fields() {
+        let Some(address) = env
+            .function_offsets
+            .get(name)
+            .and_then(|offset| backend.resolve(env, offset))
+        else {
+            continue;
+        };
+        let prologue = env.function_offsets.prologue(name).unwrap_or_default();
+        if let Err(found) = check_prologue(backend, address, &prologue) {
+            report.mark(feature, HookStatus::Failed, PROLOGUE_MISMATCH);
+            env.log.write(
+                LogLevel::Error,
+                &format!(
+                    "{}: not hooked, {name} at {address:#x} does not start with the expected prologue ({found})",
+                    feature.key()
+                ),
+            );
+            return;
+        }
+    }
+
+    match backend.create(env, feature, target) {
+        Ok(()) => {
+            report.mark(feature, HookStatus::Installed, 0);
+            env.log.write(
+                LogLevel::Info,
+                &format!(
+                    "{}: hooked {} at {target:#x}",
+                    feature.key(),
+                    describe_offset(env, offset)
+                ),
+            );
+        }
+        Err(code) => {
+            report.mark(feature, HookStatus::Failed, code);
+            env.log.write(
+                LogLevel::Error,
+                &format!(
+                    "{}: hooking {} at {target:#x} failed: {}",
+                    feature.key(),
+                    describe_offset(env, offset),
+                    backend.error_name(code)
+                ),
+            );
+        }
+    }
+}
+
+// Compare the start of a function with the prologue it is expected to have.
+// On a mismatch, what was found there instead, for the log.
+fn check_prologue(
+    backend: &impl HookBackend,
+    address: usize,
+    prologue: &Prologue,
+) -> Result<(), String> {
+    let len = prologue.len();
+    if len == 0 {
+        return Ok(());
+    }
+    let mut code = [0u8; PROLOGUE_LEN];
+    if !backend.read_code(address, &mut code[..len]) {
+        return Err("unreadable memory".to_string());
+    }
+    if prologue.matches(&code[..len]) {
+        Ok(())
+    } else {
+        let found: Vec<String> = code[..len].iter().map(|b| format!("{b:02X}")).collect();
+        Err(found.join(" "))
+    }
+}
+
+// Turn a created hook on or off as the settings change
+fn switch_hook(
+    env: &IslandEnvironment,
+    backend: &mut impl HookBackend,
+    feature: Feature,
+    on: bool,
+) {
+    let state = if on { "on" } else { "off" };
+    match backend.switch(feature, on) {
+        Ok(()) => {
+            let status = if on {
+                HookStatus::Installed
+            } else {
+                HookStatus::Inactive
+            };
+            env.hook_report.mark(feature, status, 0);
+            env.log.write(
+                LogLevel::Info,
+                &format!("{}: hook turned {state}", feature.key()),
+            );
+        }
+        Err(code) => {
+            // A hook that would not turn off is still installed
+            if on {
+                env.hook_report.mark(feature, HookStatus::Failed, code);
+            }
+            env.log.write(
+                LogLevel::Error,
+                &format!(
+                    "{}: turning the hook {state} failed: {}",
+                    feature.key(),
+                    backend.error_name(code)
+                ),
+            );
+        }
+    }
+}
+
+/// Run one command from the launcher. Unload only succeeds here; unhooking is
+/// up to the caller.
+pub fn run_command(
+    env: &IslandEnvironment,
+    backend: &mut impl HookBackend,
+    settings: &Settings,
+    command: u32,
+    argument: u32,
+) -> (CommandResult, u32) {
+    let installed = || env.hook_report.installed.load(Ordering::Acquire);
+    match Command::from_code(command) {
+        None => (CommandResult::UnknownCommand, 0),
+        Some(Command::ReResolveOffsets) => {
+            sync_hooks(env, backend, settings, true);
+            (CommandResult::Ok, installed())
+        }
+        Some(Command::DisableHook) => match Feature::from_index(argument) {
+            Some(feature)
+                if backend.created(feature)
+                    && env.hook_report.status(feature) == HookStatus::Installed =>
+            {
+                // Turned off rather than removed, so the trampoline stays valid
+                match backend.switch(feature, false) {
+                    Ok(()) => {
+                        env.hook_report.mark(feature, HookStatus::Disabled, 0);
+                        (CommandResult::Ok, 0)
+                    }
+                    Err(code) => (CommandResult::HookError, code as u32),
+                }
+            }
+            Some(_) => (CommandResult::NotInstalled, 0),
+            None => (CommandResult::InvalidArgument, 0),
+        },
+        Some(Command::Unload) => (CommandResult::Ok, 0),
+        Some(Command::DumpStatus) => {
+            for feature in Feature::ALL {
+                env.log.write(
+                    LogLevel::Info,
+                    &format!(
+                        "{}: {:?}, MinHook status {}",
+                        feature.key(),
+                        env.hook_report.status(feature),
+                        env.hook_report.minhook_status(feature)
+                    ),
+                );
+            }
+            (CommandResult::Ok, installed())
+        }
+    }
+}
+
+/// Run the commands the launcher has queued, oldest first, acknowledging each.
+/// An Unload stops the queue and is returned unacknowledged, as its slot, for
+/// the caller to acknowledge once the hooks are gone.
+pub fn serve_commands(
+    env: &IslandEnvironment,
+    backend: &mut impl HookBackend,
+    settings: &Settings,
+) -> Option<usize> {
+    while let Some((slot, command, argument)) = env.commands.next_pending() {
+        let (result, value) = run_command(env, backend, settings, command, argument);
+        let name = Command::from_code(command).map_or("unknown", Command::key);
+        env.log.write(
+            if result == CommandResult::Ok {
+                LogLevel::Info
+            } else {
+                LogLevel::Warn
+            },
+            &format!("{name}({argument}): {} {value}", result.name()),
+        );
+        if command == Command::Unload as u32 {
+            return Some(slot);
+        }
+        env.commands.complete(slot, result, value);
+    }
+    None
+}
@@ -0,0 +1,1410 @@
+//! Layout of the shared memory between the launcher and hutao_minhook.
+//!
+//! Both sides map the same bytes, so every type here is `#[repr(C)]` and the
+//! layout is pinned by the assertions at the bottom of this file. A change that
+//! moves a field fails to compile until the assertions are updated with it.
+//!
+//! Creating and opening the mapping (`SharedEnvironment`), what the hooked
+//! functions do with the settings (`endpoints`) and when the hooks go in
+//! (`hooks`) live here as well, so the simulator runs the same code as the
+//! launcher and the DLL on any platform.
+
+pub mod endpoints;
+pub mod hooks;
+mod shm;
+
+pub use shm::SharedEnvironment;
+
+use std::mem::{offset_of, size_of};
+use std::sync::atomic::{AtomicI32, AtomicU8, AtomicU32, AtomicU64, Ordering, fence};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Prefix of the mapping names; see `shared_memory_name`.
+pub const SHARED_MEMORY_NAME: &str = "4F3E8543-40F7-4808-82DC-21E48A6037A7";
+
+/// Name of the mapping for the game process `pid`. Each hooked game gets its
+/// own, so several can run side by side with their own settings. On Windows
+/// the name lives in the session-local namespace.
+#[cfg(windows)]
+pub fn shared_memory_name(pid: u32) -> String {
+    format!("Local\\{SHARED_MEMORY_NAME}-{pid}")
+}
+
+#[cfg(unix)]
+pub fn shared_memory_name(pid: u32) -> String {
+    format!("/{SHARED_MEMORY_NAME}-{pid}")
+}
+
+pub const ISLAND_MAGIC: u32 = u32::from_le_bytes(*b"ISLE");
+/// Bump whenever the layout after `IslandHeader` or its meaning changes.
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// A function address inside one of the modules listed in `IslandEnvironment::modules`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModuleOffset {
+    /// Index into `IslandEnvironment::modules`; 0 is the game executable.
+    pub module: u32,
+    pub reserved: u32,
+    /// Zero means the function is not available.
+    pub rva: u64,
+}
+
+/// Longest prologue the DLL compares before hooking a function.
+pub const PROLOGUE_LEN: usize = 16;
+
+/// Bytes a function is expected to start with. Only the bits set in `mask` are
+/// compared, so an all-zero prologue accepts any function.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Prologue {
+    pub bytes: [u8; PROLOGUE_LEN],
+    pub mask: [u8; PROLOGUE_LEN],
+}
+
+impl Prologue {
+    /// From a byte pattern in which `None` matches any byte. Bytes past
+    /// `PROLOGUE_LEN` are not compared.
+    pub fn from_pattern(pattern: &[Option<u8>]) -> Self {
+        let mut prologue = Self::default();
+        for (i, byte) in pattern.iter().take(PROLOGUE_LEN).enumerate() {
+            if let Some(byte) = byte {
+                prologue.bytes[i] = *byte;
+                prologue.mask[i] = 0xFF;
+            }
+        }
+        prologue
+    }
+
+    /// Number of bytes to read from the function: up to the last compared one.
+    pub fn len(&self) -> usize {
+        self.mask.iter().rposition(|&m| m != 0).map_or(0, |i| i + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether `code`, read from the start of the function, fits the prologue.
+    pub fn matches(&self, code: &[u8]) -> bool {
+        code.len() >= self.len()
+            && code
+                .iter()
+                .zip(self.bytes.iter().zip(&self.mask))
+                .all(|(b, (expected, mask))| b & mask == expected & mask)
+    }
+}
+
+/// Number of functions in `FunctionOffsets`.
+pub const FUNCTION_COUNT: usize = 9;
+
+/// Offsets of every function a feature hooks or calls, in the order of
+/// `FunctionOffsets::FIELD_NAMES`, with the prologue each is expected to start
+/// with.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FunctionOffsets {
+    pub offsets: [ModuleOffset; FUNCTION_COUNT],
+    pub prologues: [Prologue; FUNCTION_COUNT],
+}
+
+impl FunctionOffsets {
+    /// The offset keys. Appending one bumps `PROTOCOL_VERSION`; the features
+    /// that use it name it in `FEATURES`.
+    pub const FIELD_NAMES: [&str; FUNCTION_COUNT] = [
+        "find_string",
+        "set_field_of_view",
+        "set_enable_fog_rendering",
+        "set_target_frame_rate",
+        "open_team",
+        "open_team_page_accordingly",
+        "check_can_enter",
+        "craft_entry",
+        "craft_entry_partner",
+    ];
+
+    /// Position of offset key `name` in `offsets`.
+    pub fn index(name: &str) -> Option<usize> {
+        Self::FIELD_NAMES.iter().position(|&field| field == name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<ModuleOffset> {
+        Self::index(name).map(|index| self.offsets[index])
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut ModuleOffset> {
+        Self::index(name).map(|index| &mut self.offsets[index])
+    }
+
+    pub fn prologue(&self, name: &str) -> Option<Prologue> {
+        Self::index(name).map(|index| self.prologues[index])
+    }
+
+    pub fn prologue_mut(&mut self, name: &str) -> Option<&mut Prologue> {
+        Self::index(name).map(|index| &mut self.prologues[index])
+    }
+}
+
+/// A hooked feature together with every function it needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    FieldOfView,
+    TeamProgress,
+    CraftRedirect,
+}
+
+/// Everything about a feature that the launcher and the DLL share. The DLL
+/// adds the detour; the rest is driven from here.
+#[derive(Debug)]
+pub struct FeatureSpec {
+    /// Short name for the command line.
+    pub key: &'static str,
+    /// Name shown in the launcher.
+    pub name: &'static str,
+    /// Offset key of the function the DLL hooks. Its trampoline is what the
+    /// detour calls to run the game's own code.
+    pub hooked: &'static str,
+    /// Offset keys of the other game functions the detour calls.
+    pub helpers: &'static [&'static str],
+    /// `Settings` fields the detour acts on. The feature has something to do
+    /// while any of them differs from the game's own, and is switched off in
+    /// the launcher by putting them back.
+    pub settings: &'static [&'static str],
+    /// Whether the game is back to its own values after the detour's last
+    /// call. The game keeps what a detour sets, so a hook with nothing left
+    /// to do stays on until this holds.
+    pub restored: fn(&IslandEnvironment) -> bool,
+}
+
+impl FeatureSpec {
+    /// Whether the settings give the detour anything to do. The DLL only
+    /// hooks the function while they do.
+    pub fn active(&self, settings: &Settings) -> bool {
+        let mut game = *settings;
+        self.copy_settings(&mut game, &Settings::default());
+        game != *settings
+    }
+
+    /// Copy the fields this feature acts on from `from` into `to`.
+    pub fn copy_settings(&self, to: &mut Settings, from: &Settings) {
+        for name in self.settings {
+            to.copy_field(from, name);
+        }
+    }
+}
+
+/// Indexed by `Feature as usize`.
+pub const FEATURES: [FeatureSpec; 3] = [
+    FeatureSpec {
+        key: "field_of_view",
+        name: "FOV / FPS / Fog",
+        hooked: "set_field_of_view",
+        helpers: &["set_enable_fog_rendering", "set_target_frame_rate"],
+        settings: &[
+            "field_of_view",
+            "fix_low_fov_scene",
+            "disable_fog",
+            "target_frame_rate",
+        ],
+        restored: endpoints::field_of_view_restored,
+    },
+    FeatureSpec {
+        key: "team_progress",
+        name: "Remove Team Animation",
+        hooked: "open_team",
+        helpers: &["open_team_page_accordingly", "check_can_enter"],
+        settings: &["remove_open_team_progress"],
+        restored: endpoints::nothing_to_restore,
+    },
+    FeatureSpec {
+        key: "craft_redirect",
+        name: "Redirect Crafting Table",
+        hooked: "craft_entry",
+        helpers: &["craft_entry_partner", "find_string"],
+        settings: &["redirect_craft_entry"],
+        restored: endpoints::nothing_to_restore,
+    },
+];
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [
+        Feature::FieldOfView,
+        Feature::TeamProgress,
+        Feature::CraftRedirect,
+    ];
+
+    pub fn spec(self) -> &'static FeatureSpec {
+        &FEATURES[self as usize]
+    }
+
+    /// Bit of this feature in the `HookReport` masks.
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    /// Short name for the command line.
+    pub fn key(self) -> &'static str {
+        self.spec().key
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.key() == key)
+    }
+
+    pub fn from_index(index: u32) -> Option<Self> {
+        Self::ALL.get(index as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        self.spec().name
+    }
+
+    /// Offset keys of the hooked function and then its helpers.
+    pub fn offset_fields(self) -> impl Iterator<Item = &'static str> {
+        let spec = self.spec();
+        std::iter::once(spec.hooked).chain(spec.helpers.iter().copied())
+    }
+}
+
+/// Lifecycle of the DLL, stored as a `u32` code in `IslandHeader::state`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IslandState {
+    None = 0,
+    Error = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl IslandState {
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Self::None),
+            1 => Some(Self::Error),
+            2 => Some(Self::Started),
+            3 => Some(Self::Stopped),
+            _ => None,
+        }
+    }
+}
+
+/// Why the DLL refused to run, reported in `IslandHeader::error`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IslandError {
+    None = 0,
+    BadMagic = 1,
+    VersionMismatch = 2,
+    SizeMismatch = 3,
+    HookInstallFailed = 4,
+}
+
+impl IslandError {
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Self::None),
+            1 => Some(Self::BadMagic),
+            2 => Some(Self::VersionMismatch),
+            3 => Some(Self::SizeMismatch),
+            4 => Some(Self::HookInstallFailed),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "no error",
+            Self::BadMagic => "not an island environment",
+            Self::VersionMismatch => "protocol version mismatch",
+            Self::SizeMismatch => "environment size mismatch",
+            Self::HookInstallFailed => "hook installation failed",
+        }
+    }
+}
+
+/// First bytes of the mapping. This layout is the same in every protocol
+/// version, so either side can tell whether it understands the rest.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IslandHeader {
+    pub magic: u32,
+    /// Protocol version the launcher wrote the environment with.
+    pub version: u32,
+    /// `size_of::<IslandEnvironment>()` in the launcher.
+    pub size: u32,
+    /// Protocol version of the DLL, written back once it has checked the header.
+    pub dll_version: u32,
+    /// An `IslandState` code. The other side may write anything here, so it
+    /// is kept as a plain integer and read through `state()`.
+    pub state: u32,
+    /// An `IslandError` code.
+    pub error: u32,
+    /// Win32 error that came with `error`, if any.
+    pub last_error: u32,
+    pub reserved: u32,
+}
+
+impl IslandHeader {
+    /// A header describing this build of the protocol.
+    pub fn new() -> Self {
+        Self {
+            magic: ISLAND_MAGIC,
+            version: PROTOCOL_VERSION,
+            size: size_of::<IslandEnvironment>() as u32,
+            dll_version: 0,
+            state: IslandState::None as u32,
+            error: IslandError::None as u32,
+            last_error: 0,
+            reserved: 0,
+        }
+    }
+
+    /// Check a header written by the other side against this build.
+    pub fn validate(&self) -> Result<(), IslandError> {
+        if self.magic != ISLAND_MAGIC {
+            Err(IslandError::BadMagic)
+        } else if self.version != PROTOCOL_VERSION {
+            Err(IslandError::VersionMismatch)
+        } else if self.size != size_of::<IslandEnvironment>() as u32 {
+            Err(IslandError::SizeMismatch)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The DLL state, or `None` if the code is not one this build knows.
+    pub fn state(&self) -> Option<IslandState> {
+        IslandState::from_code(self.state)
+    }
+}
+
+impl Default for IslandHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the DLL did with the hook of one feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookStatus {
+    /// The DLL has not got to this feature yet.
+    Pending,
+    Installed,
+    /// No usable offset, so the hook was not attempted.
+    Skipped,
+    /// MinHook refused, or the function did not start with its prologue;
+    /// see `HookReport::minhook_status`.
+    Failed,
+    /// Installed, then turned off on request.
+    Disabled,
+    /// Not hooked because the settings leave the feature nothing to do. The
+    /// DLL hooks it once they change.
+    Inactive,
+}
+
+/// `HookReport::minhook_status` of a feature that was not hooked because its
+/// function did not start with the expected prologue. MinHook was not called.
+pub const PROLOGUE_MISMATCH: i32 = i32::MIN;
+
+/// Room for features in `HookReport`; `Feature::ALL` must fit.
+pub const MAX_FEATURES: usize = 8;
+
+/// Written by the DLL, read live by the launcher.
+#[repr(C)]
+#[derive(Debug)]
+pub struct HookReport {
+    /// `Feature::bit()` masks.
+    pub installed: AtomicU32,
+    pub skipped: AtomicU32,
+    pub failed: AtomicU32,
+    pub disabled: AtomicU32,
+    pub inactive: AtomicU32,
+    /// MinHook status code of a failed feature, indexed by `Feature as usize`.
+    pub minhook_status: [AtomicI32; MAX_FEATURES],
+    /// Incremented by the DLL about once a second while it runs.
+    pub heartbeat: AtomicU64,
+}
+
+impl HookReport {
+    pub fn mark(&self, feature: Feature, status: HookStatus, minhook_status: i32) {
+        let bit = feature.bit();
+        self.minhook_status[feature as usize].store(minhook_status, Ordering::Relaxed);
+        for (mask, set) in [
+            (&self.installed, status == HookStatus::Installed),
+            (&self.skipped, status == HookStatus::Skipped),
+            (&self.failed, status == HookStatus::Failed),
+            (&self.disabled, status == HookStatus::Disabled),
+            (&self.inactive, status == HookStatus::Inactive),
+        ] {
+            if set {
+                mask.fetch_or(bit, Ordering::Release);
+            } else {
+                mask.fetch_and(!bit, Ordering::Release);
+            }
+        }
+    }
+
+    pub fn status(&self, feature: Feature) -> HookStatus {
+        let bit = feature.bit();
+        if self.installed.load(Ordering::Acquire) & bit != 0 {
+            HookStatus::Installed
+        } else if self.disabled.load(Ordering::Acquire) & bit != 0 {
+            HookStatus::Disabled
+        } else if self.failed.load(Ordering::Acquire) & bit != 0 {
+            HookStatus::Failed
+        } else if self.skipped.load(Ordering::Acquire) & bit != 0 {
+            HookStatus::Skipped
+        } else if self.inactive.load(Ordering::Acquire) & bit != 0 {
+            HookStatus::Inactive
+        } else {
+            HookStatus::Pending
+        }
+    }
+
+    pub fn minhook_status(&self, feature: Feature) -> i32 {
+        self.minhook_status[feature as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// User settings, always copied in and out of `SettingsBlock` as a whole.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub field_of_view: f32,
+    pub fix_low_fov_scene: bool,
+    pub disable_fog: bool,
+    pub target_frame_rate: i32,
+    pub remove_open_team_progress: bool,
+    pub redirect_craft_entry: bool,
+}
+
+impl Settings {
+    /// Field names, for the features that list what they act on.
+    pub const FIELD_NAMES: [&str; 6] = [
+        "field_of_view",
+        "fix_low_fov_scene",
+        "disable_fog",
+        "target_frame_rate",
+        "remove_open_team_progress",
+        "redirect_craft_entry",
+    ];
+
+    /// Copy the field called `name` from `from`. Panics on names outside `FIELD_NAMES`.
+    pub fn copy_field(&mut self, from: &Settings, name: &str) {
+        match name {
+            "field_of_view" => self.field_of_view = from.field_of_view,
+            "fix_low_fov_scene" => self.fix_low_fov_scene = from.fix_low_fov_scene,
+            "disable_fog" => self.disable_fog = from.disable_fog,
+            "target_frame_rate" => self.target_frame_rate = from.target_frame_rate,
+            "remove_open_team_progress" => {
+                self.remove_open_team_progress = from.remove_open_team_progress
+            }
+            "redirect_craft_entry" => self.redirect_craft_entry = from.redirect_craft_entry,
+            _ => panic!("unknown settings field {name}"),
+        }
+    }
+}
+
+impl Default for Settings {
+    /// The game's own behaviour.
+    fn default() -> Self {
+        Self {
+            field_of_view: 45.0,
+            fix_low_fov_scene: false,
+            disable_fog: false,
+            target_frame_rate: 60,
+            remove_open_team_progress: false,
+            redirect_craft_entry: false,
+        }
+    }
+}
+
+/// How often a reader retries before giving up on a write in progress.
+const SEQLOCK_READ_ATTEMPTS: u32 = 64;
+
+/// Settings behind a seqlock: the launcher is the only writer and the DLL
+/// only ever sees a complete `Settings`, never a half-applied change.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SettingsBlock {
+    /// Odd while a write is in progress; every completed write adds 2.
+    pub sequence: AtomicU32,
+    /// `f32` bits.
+    pub field_of_view: AtomicU32,
+    pub fix_low_fov_scene: AtomicI32, // BOOL
+    pub disable_fog: AtomicI32,       // BOOL
+    pub target_frame_rate: AtomicI32,
+    pub remove_open_team_progress: AtomicI32, // BOOL
+    pub redirect_craft_entry: AtomicI32,      // BOOL
+    pub reserved: u32,
+}
+
+impl SettingsBlock {
+    /// Publish `settings` as the next generation. Must not race another writer.
+    pub fn write(&self, settings: &Settings) {
+        let sequence = self.sequence.load(Ordering::Relaxed) | 1;
+        self.sequence.store(sequence, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.field_of_view
+            .store(settings.field_of_view.to_bits(), Ordering::Relaxed);
+        self.fix_low_fov_scene
+            .store(settings.fix_low_fov_scene as i32, Ordering::Relaxed);
+        self.disable_fog
+            .store(settings.disable_fog as i32, Ordering::Relaxed);
+        self.target_frame_rate
+            .store(settings.target_frame_rate, Ordering::Relaxed);
+        self.remove_open_team_progress
+            .store(settings.remove_open_team_progress as i32, Ordering::Relaxed);
+        self.redirect_craft_entry
+            .store(settings.redirect_craft_entry as i32, Ordering::Relaxed);
+
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Release);
+    }
+
+    /// A consistent snapshot with its generation, or `None` if a write stayed
+    /// in progress for every attempt.
+    pub fn read(&self) -> Option<(u32, Settings)> {
+        for _ in 0..SEQLOCK_READ_ATTEMPTS {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                let settings = Settings {
+                    field_of_view: f32::from_bits(self.field_of_view.load(Ordering::Relaxed)),
+                    fix_low_fov_scene: self.fix_low_fov_scene.load(Ordering::Relaxed) != 0,
+                    disable_fog: self.disable_fog.load(Ordering::Relaxed) != 0,
+                    target_frame_rate: self.target_frame_rate.load(Ordering::Relaxed),
+                    remove_open_team_progress: self
+                        .remove_open_team_progress
+                        .load(Ordering::Relaxed)
+                        != 0,
+                    redirect_craft_entry: self.redirect_craft_entry.load(Ordering::Relaxed) != 0,
+                };
+                fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) == before {
+                    return Some((before / 2, settings));
+                }
+            }
+            std::hint::spin_loop();
+        }
+        None
+    }
+}
+
+/// What the game last ran with, as written back by the field of view hook.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EffectiveValues {
+    /// The value the game passed to `set_field_of_view`.
+    pub original_field_of_view: f32,
+    /// The value passed on in its place.
+    pub field_of_view: f32,
+    pub enable_fog: bool,
+    pub target_frame_rate: i32,
+    pub low_fov_scene: bool,
+}
+
+/// Effective values behind a seqlock, written by the DLL and read by the
+/// launcher. A generation of 0 means the hook has not run yet.
+#[repr(C)]
+#[derive(Debug)]
+pub struct EffectiveBlock {
+    /// Odd while a write is in progress; every completed write adds 2.
+    pub sequence: AtomicU32,
+    /// `f32` bits.
+    pub original_field_of_view: AtomicU32,
+    /// `f32` bits.
+    pub field_of_view: AtomicU32,
+    pub enable_fog: AtomicI32, // BOOL
+    pub target_frame_rate: AtomicI32,
+    pub low_fov_scene: AtomicI32, // BOOL
+    pub reserved: [u32; 2],
+}
+
+impl EffectiveBlock {
+    /// Publish `values`. The hook may run on several game threads; a write
+    /// that finds another in progress is dropped, the next call catches up.
+    pub fn write(&self, values: &EffectiveValues) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        if sequence & 1 != 0
+            || self
+                .sequence
+                .compare_exchange(sequence, sequence | 1, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        fence(Ordering::Release);
+
+        self.original_field_of_view
+            .store(values.original_field_of_view.to_bits(), Ordering::Relaxed);
+        self.field_of_view
+            .store(values.field_of_view.to_bits(), Ordering::Relaxed);
+        self.enable_fog
+            .store(values.enable_fog as i32, Ordering::Relaxed);
+        self.target_frame_rate
+            .store(values.target_frame_rate, Ordering::Relaxed);
+        self.low_fov_scene
+            .store(values.low_fov_scene as i32, Ordering::Relaxed);
+
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// A consistent snapshot with its generation, or `None` if a write stayed
+    /// in progress for every attempt.
+    pub fn read(&self) -> Option<(u32, EffectiveValues)> {
+        for _ in 0..SEQLOCK_READ_ATTEMPTS {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                let values = EffectiveValues {
+                    original_field_of_view: f32::from_bits(
+                        self.original_field_of_view.load(Ordering::Relaxed),
+                    ),
+                    field_of_view: f32::from_bits(self.field_of_view.load(Ordering::Relaxed)),
+                    enable_fog: self.enable_fog.load(Ordering::Relaxed) != 0,
+                    target_frame_rate: self.target_frame_rate.load(Ordering::Relaxed),
+                    low_fov_scene: self.low_fov_scene.load(Ordering::Relaxed) != 0,
+                };
+                fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) == before {
+                    return Some((before / 2, values));
+                }
+            }
+            std::hint::spin_loop();
+        }
+        None
+    }
+}
+
+/// Explicit requests from the launcher or the command line to the DLL.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Retry the hooks of features that are not installed, e.g. after a module loaded.
+    ReResolveOffsets = 1,
+    /// Turn off the hook of the feature given as argument (`Feature as u32`).
+    DisableHook = 2,
+    /// Remove every hook and unload the DLL.
+    Unload = 3,
+    /// Reply with the installed mask, showing the command loop is alive.
+    DumpStatus = 4,
+}
+
+impl Command {
+    pub const ALL: [Command; 4] = [
+        Command::ReResolveOffsets,
+        Command::DisableHook,
+        Command::Unload,
+        Command::DumpStatus,
+    ];
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| *c as u32 == code)
+    }
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Command::ReResolveOffsets => "re-resolve",
+            Command::DisableHook => "disable-hook",
+            Command::Unload => "unload",
+            Command::DumpStatus => "dump-status",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.key() == key)
+    }
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandResult {
+    Ok = 0,
+    UnknownCommand = 1,
+    InvalidArgument = 2,
+    /// MinHook failed; the reply value is its status.
+    HookError = 3,
+    NotInstalled = 4,
+}
+
+impl CommandResult {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Ok),
+            1 => Some(Self::UnknownCommand),
+            2 => Some(Self::InvalidArgument),
+            3 => Some(Self::HookError),
+            4 => Some(Self::NotInstalled),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::UnknownCommand => "unknown command",
+            Self::InvalidArgument => "invalid argument",
+            Self::HookError => "MinHook error",
+            Self::NotInstalled => "hook not installed",
+        }
+    }
+}
+
+/// Acknowledgement of one command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandReply {
+    /// `CommandResult` code.
+    pub result: i32,
+    pub value: u32,
+}
+
+const SLOT_FREE: u32 = 0;
+const SLOT_WRITING: u32 = 1;
+const SLOT_PENDING: u32 = 2;
+const SLOT_RUNNING: u32 = 3;
+const SLOT_DONE: u32 = 4;
+/// The submitter stopped waiting; the DLL frees the slot when it is done.
+const SLOT_ABANDONED: u32 = 5;
+
+pub const COMMAND_SLOTS: usize = 8;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct CommandSlot {
+    pub state: AtomicU32,
+    /// Orders pending commands; taken from `CommandQueue::next_ticket`.
+    pub ticket: AtomicU32,
+    pub command: AtomicU32,
+    pub argument: AtomicU32,
+    pub result: AtomicI32,
+    pub value: AtomicU32,
+}
+
+/// Commands to the DLL. Any number of processes may submit; only the DLL
+/// runs them, oldest first.
+#[repr(C)]
+#[derive(Debug)]
+pub struct CommandQueue {
+    pub next_ticket: AtomicU32,
+    pub reserved: u32,
+    pub slots: [CommandSlot; COMMAND_SLOTS],
+}
+
+impl CommandQueue {
+    /// Queue a command. Returns the slot to wait on, or `None` if every slot is busy.
+    pub fn submit(&self, command: Command, argument: u32) -> Option<usize> {
+        let (index, slot) = self.slots.iter().enumerate().find(|(_, slot)| {
+            slot.state
+                .compare_exchange(
+                    SLOT_FREE,
+                    SLOT_WRITING,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        })?;
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        slot.ticket.store(ticket, Ordering::Relaxed);
+        slot.command.store(command as u32, Ordering::Relaxed);
+        slot.argument.store(argument, Ordering::Relaxed);
+        slot.state.store(SLOT_PENDING, Ordering::Release);
+        Some(index)
+    }
+
+    /// Take the reply of a submitted command once it is done, freeing the slot.
+    pub fn take_reply(&self, index: usize) -> Option<CommandReply> {
+        let slot = &self.slots[index];
+        if slot.state.load(Ordering::Acquire) != SLOT_DONE {
+            return None;
+        }
+        let reply = CommandReply {
+            result: slot.result.load(Ordering::Relaxed),
+            value: slot.value.load(Ordering::Relaxed),
+        };
+        slot.state.store(SLOT_FREE, Ordering::Release);
+        Some(reply)
+    }
+
+    /// Stop waiting for a command. A command that has not started is dropped.
+    pub fn abandon(&self, index: usize) {
+        let state = &self.slots[index].state;
+        let dropped = state
+            .compare_exchange(SLOT_PENDING, SLOT_FREE, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        let running = !dropped
+            && state
+                .compare_exchange(
+                    SLOT_RUNNING,
+                    SLOT_ABANDONED,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok();
+        if !dropped && !running {
+            // Finished in the meantime
+            self.take_reply(index);
+        }
+    }
+
+    /// Submit a command and wait for its reply.
+    pub fn send(
+        &self,
+        command: Command,
+        argument: u32,
+        timeout: Duration,
+    ) -> Result<CommandReply, String> {
+        let index = self
+            .submit(command, argument)
+            .ok_or("Every command slot is busy")?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(reply) = self.take_reply(index) {
+                return Ok(reply);
+            }
+            if Instant::now() >= deadline {
+                self.abandon(index);
+                return Err(format!("No reply to {} within {timeout:?}", command.key()));
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// DLL side: claim the oldest pending command as `(slot, command code, argument)`.
+    pub fn next_pending(&self) -> Option<(usize, u32, u32)> {
+        loop {
+            let (index, slot) = self
+                .slots
+                .iter()
+                .enumerate()
+                .filter(|(_, slot)| slot.state.load(Ordering::Acquire) == SLOT_PENDING)
+                .min_by_key(|(_, slot)| slot.ticket.load(Ordering::Relaxed))?;
+            // The submitter may have abandoned it in the meantime
+            if slot
+                .state
+                .compare_exchange(
+                    SLOT_PENDING,
+                    SLOT_RUNNING,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some((
+                    index,
+                    slot.command.load(Ordering::Relaxed),
+                    slot.argument.load(Ordering::Relaxed),
+                ));
+            }
+        }
+    }
+
+    /// DLL side: acknowledge a command claimed with `next_pending`.
+    pub fn complete(&self, index: usize, result: CommandResult, value: u32) {
+        let slot = &self.slots[index];
+        slot.result.store(result as i32, Ordering::Relaxed);
+        slot.value.store(value, Ordering::Relaxed);
+        if slot
+            .state
+            .compare_exchange(
+                SLOT_RUNNING,
+                SLOT_DONE,
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            // Nobody is waiting any more
+            slot.state.store(SLOT_FREE, Ordering::Release);
+        }
+    }
+}
+
+/// Severity of a log record from the DLL.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Error),
+            1 => Some(Self::Warn),
+            2 => Some(Self::Info),
+            3 => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+        }
+    }
+}
+
+pub const LOG_RECORDS: usize = 64;
+/// Longer messages are cut at a character boundary.
+pub const LOG_MESSAGE_LEN: usize = 120;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct LogRecord {
+    /// `2 * index + 1` while record `index` is written, `2 * index + 2` once it is complete.
+    pub sequence: AtomicU64,
+    /// `LogLevel` code.
+    pub level: AtomicU32,
+    pub len: AtomicU32,
+    pub message: [AtomicU8; LOG_MESSAGE_LEN],
+}
+
+/// One record read back from a `LogRing`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub level: LogLevel,
+    pub message: String,
+}
+
+/// Records taken by one `LogRing::drain`.
+#[derive(Debug, Default)]
+pub struct DrainedLog {
+    pub entries: Vec<LogEntry>,
+    /// Records overwritten before they were read.
+    pub lost: u64,
+}
+
+/// Log records from the DLL. Writers never block: the oldest record is
+/// overwritten once the ring is full, and the reader counts what it missed.
+#[repr(C)]
+#[derive(Debug)]
+pub struct LogRing {
+    pub next_index: AtomicU64,
+    pub records: [LogRecord; LOG_RECORDS],
+}
+
+impl LogRing {
+    /// Append a record. Safe to call from any thread of the DLL.
+    pub fn write(&self, level: LogLevel, message: &str) {
+        let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+        let record = &self.records[(index % LOG_RECORDS as u64) as usize];
+        record.sequence.store(2 * index + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        let mut len = message.len().min(LOG_MESSAGE_LEN);
+        while !message.is_char_boundary(len) {
+            len -= 1;
+        }
+        record.level.store(level as u32, Ordering::Relaxed);
+        record.len.store(len as u32, Ordering::Relaxed);
+        for (byte, value) in record.message.iter().zip(&message.as_bytes()[..len]) {
+            byte.store(*value, Ordering::Relaxed);
+        }
+
+        record.sequence.store(2 * index + 2, Ordering::Release);
+    }
+
+    /// Read the records after `cursor` and advance it. A record that is still
+    /// being written ends the drain; it is picked up by the next one.
+    pub fn drain(&self, cursor: &mut u64) -> DrainedLog {
+        let mut drained = DrainedLog::default();
+        let head = self.next_index.load(Ordering::Acquire);
+        if head.saturating_sub(*cursor) > LOG_RECORDS as u64 {
+            drained.lost = head - LOG_RECORDS as u64 - *cursor;
+            *cursor = head - LOG_RECORDS as u64;
+        }
+        while *cursor < head {
+            let record = &self.records[(*cursor % LOG_RECORDS as u64) as usize];
+            let expected = 2 * *cursor + 2;
+            let before = record.sequence.load(Ordering::Acquire);
+            if before < expected {
+                break;
+            }
+            if before == expected {
+                let level = record.level.load(Ordering::Relaxed);
+                let len = (record.len.load(Ordering::Relaxed) as usize).min(LOG_MESSAGE_LEN);
+                let bytes: Vec<u8> = record.message[..len]
+                    .iter()
+                    .map(|byte| byte.load(Ordering::Relaxed))
+                    .collect();
+                fence(Ordering::Acquire);
+                if record.sequence.load(Ordering::Relaxed) == expected {
+                    drained.entries.push(LogEntry {
+                        level: LogLevel::from_code(level).unwrap_or(LogLevel::Info),
+                        message: String::from_utf8_lossy(&bytes).into_owned(),
+                    });
+                } else {
+                    drained.lost += 1;
+                }
+            } else {
+                drained.lost += 1;
+            }
+            *cursor += 1;
+        }
+        drained
+    }
+}
+
+pub const MAX_MODULES: usize = 8;
+pub const MODULE_NAME_LEN: usize = 64;
+
+/// NUL-terminated ASCII module names. Entry 0 is always the game executable and stays empty.
+pub type ModuleTable = [[u8; MODULE_NAME_LEN]; MAX_MODULES];
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct IslandEnvironment {
+    pub header: IslandHeader,
+    pub hook_report: HookReport,
+    pub modules: ModuleTable,
+    pub function_offsets: FunctionOffsets,
+    pub settings: SettingsBlock,
+    pub commands: CommandQueue,
+    pub log: LogRing,
+    pub effective: EffectiveBlock,
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn contains(names: &[&str], name: &str) -> bool {
+    let mut i = 0;
+    while i < names.len() {
+        if str_eq(names[i], name) {
+            return true;
+        }
+        i += 1;
+    }
+    false
+}
+
+const _: () = {
+    assert!(size_of::<IslandHeader>() == 32);
+    assert!(offset_of!(IslandHeader, magic) == 0);
+    assert!(offset_of!(IslandHeader, version) == 4);
+    assert!(offset_of!(IslandHeader, size) == 8);
+    assert!(offset_of!(IslandHeader, dll_version) == 12);
+    assert!(offset_of!(IslandHeader, state) == 16);
+    assert!(offset_of!(IslandHeader, error) == 20);
+    assert!(offset_of!(IslandHeader, last_error) == 24);
+
+    assert!(Feature::ALL.len() <= MAX_FEATURES);
+    assert!(FEATURES.len() == Feature::ALL.len());
+    assert!(size_of::<HookReport>() == 64);
+    assert!(offset_of!(HookReport, installed) == 0);
+    assert!(offset_of!(HookReport, skipped) == 4);
+    assert!(offset_of!(HookReport, failed) == 8);
+    assert!(offset_of!(HookReport, disabled) == 12);
+    assert!(offset_of!(HookReport, inactive) == 16);
+    assert!(offset_of!(HookReport, minhook_status) == 20);
+    assert!(offset_of!(HookReport, heartbeat) == 56);
+
+    assert!(size_of::<ModuleOffset>() == 16);
+    assert!(offset_of!(ModuleOffset, module) == 0);
+    assert!(offset_of!(ModuleOffset, rva) == 8);
+
+    assert!(size_of::<Prologue>() == 2 * PROLOGUE_LEN);
+    assert!(offset_of!(Prologue, mask) == PROLOGUE_LEN);
+
+    assert!(size_of::<FunctionOffsets>() == (16 + 2 * PROLOGUE_LEN) * FUNCTION_COUNT);
+    assert!(offset_of!(FunctionOffsets, prologues) == 16 * FUNCTION_COUNT);
+
+    // Every name a feature declares exists
+    let mut feature = 0;
+    while feature < FEATURES.len() {
+        let spec = &FEATURES[feature];
+        assert!(contains(&FunctionOffsets::FIELD_NAMES, spec.hooked));
+        let mut i = 0;
+        while i < spec.helpers.len() {
+            assert!(contains(&FunctionOffsets::FIELD_NAMES, spec.helpers[i]));
+            i += 1;
+        }
+        let mut i = 0;
+        while i < spec.settings.len() {
+            assert!(contains(&Settings::FIELD_NAMES, spec.settings[i]));
+            i += 1;
+        }
+        feature += 1;
+    }
+
+    assert!(size_of::<SettingsBlock>() == 32);
+    assert!(offset_of!(SettingsBlock, sequence) == 0);
+    assert!(offset_of!(SettingsBlock, field_of_view) == 4);
+    assert!(offset_of!(SettingsBlock, fix_low_fov_scene) == 8);
+    assert!(offset_of!(SettingsBlock, disable_fog) == 12);
+    assert!(offset_of!(SettingsBlock, target_frame_rate) == 16);
+    assert!(offset_of!(SettingsBlock, remove_open_team_progress) == 20);
+    assert!(offset_of!(SettingsBlock, redirect_craft_entry) == 24);
+
+    assert!(size_of::<EffectiveBlock>() == 32);
+    assert!(offset_of!(EffectiveBlock, sequence) == 0);
+    assert!(offset_of!(EffectiveBlock, original_field_of_view) == 4);
+    assert!(offset_of!(EffectiveBlock, field_of_view) == 8);
+    assert!(offset_of!(EffectiveBlock, enable_fog) == 12);
+    assert!(offset_of!(EffectiveBlock, target_frame_rate) == 16);
+    assert!(offset_of!(EffectiveBlock, low_fov_scene) == 20);
+
+    assert!(size_of::<CommandSlot>() == 24);
+    assert!(size_of::<CommandQueue>() == 8 + 24 * COMMAND_SLOTS);
+    assert!(offset_of!(CommandQueue, slots) == 8);
+
+    assert!(size_of::<LogRecord>() == 136);
+    assert!(offset_of!(LogRecord, sequence) == 0);
+    assert!(offset_of!(LogRecord, level) == 8);
+    assert!(offset_of!(LogRecord, len) == 12);
+    assert!(offset_of!(LogRecord, message) == 16);
+    assert!(size_of::<LogRing>() == 8 + 136 * LOG_RECORDS);
+    assert!(offset_of!(LogRing, records) == 8);
+
+    assert!(size_of::<IslandEnvironment>() == 10016);
+    assert!(offset_of!(IslandEnvironment, header) == 0);
+    assert!(offset_of!(IslandEnvironment, hook_report) == 32);
+    assert!(offset_of!(IslandEnvironment, modules) == 96);
+    assert!(offset_of!(IslandEnvironment, function_offsets) == 608);
+    assert!(offset_of!(IslandEnvironment, settings) == 1040);
+    assert!(offset_of!(IslandEnvironment, commands) == 1072);
+    assert!(offset_of!(IslandEnvironment, log) == 1272);
+    assert!(offset_of!(IslandEnvironment, effective) == 9984);
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A block as the mapping starts out: all zero
+    fn zeroed<T>() -> Box<T> {
+        // Every block here is made of integers and atomics
+        unsafe { Box::new(std::mem::zeroed()) }
+    }
+
+    // A settings value with every field away from the game's own
+    fn changed() -> Settings {
+        Settings {
+            field_of_view: 90.0,
+            fix_low_fov_scene: true,
+            disable_fog: true,
+            target_frame_rate: 120,
+            remove_open_team_progress: true,
+            redirect_craft_entry: true,
+        }
+    }
+
+    #[test]
+    fn every_settings_field_can_be_copied() {
+        for name in Settings::FIELD_NAMES {
+            let mut settings = Settings::default();
+            settings.copy_field(&changed(), name);
+            assert_ne!(settings, Settings::default(), "{name}");
+        }
+    }
+
+    #[test]
+    fn features_are_active_while_their_own_fields_differ() {
+        assert!(
+            Feature::ALL
+                .iter()
+                .all(|f| !f.spec().active(&Settings::default()))
+        );
+        for feature in Feature::ALL {
+            let spec = feature.spec();
+            let mut settings = Settings::default();
+            spec.copy_settings(&mut settings, &changed());
+            for other in Feature::ALL {
+                assert_eq!(other.spec().active(&settings), other == feature);
+            }
+        }
+    }
+
+    #[test]
+    fn settings_read_back_what_was_written_with_the_next_generation() {
+        let block = zeroed::<SettingsBlock>();
+        assert_eq!(block.read().map(|(generation, _)| generation), Some(0));
+
+        block.write(&changed());
+        assert_eq!(block.read(), Some((1, changed())));
+        block.write(&Settings::default());
+        assert_eq!(block.read(), Some((2, Settings::default())));
+    }
+
+    #[test]
+    fn settings_are_not_read_while_a_write_is_in_progress() {
+        let block = zeroed::<SettingsBlock>();
+        block.write(&changed());
+        block.sequence.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(block.read(), None);
+
+        block.write(&Settings::default());
+        assert_eq!(block.read(), Some((2, Settings::default())));
+    }
+
+    #[test]
+    fn effective_values_read_back_and_skip_a_write_in_progress() {
+        let values = EffectiveValues {
+            original_field_of_view: 45.0,
+            field_of_view: 90.0,
+            enable_fog: false,
+            target_frame_rate: 120,
+            low_fov_scene: true,
+        };
+        let block = zeroed::<EffectiveBlock>();
+        block.write(&values);
+        assert_eq!(block.read(), Some((1, values)));
+        let later = EffectiveValues {
+            target_frame_rate: 60,
+            ..values
+        };
+        block.write(&later);
+        assert_eq!(block.read(), Some((2, later)));
+
+        // Another thread is writing: this write is dropped and nothing can be read
+        block.sequence.fetch_add(1, Ordering::Relaxed);
+        block.write(&values);
+        assert_eq!(block.read(), None);
+        block.sequence.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(block.read(), Some((3, later)));
+    }
+
+    #[test]
+    fn a_full_queue_refuses_commands() {
+        let queue = zeroed::<CommandQueue>();
+        for index in 0..COMMAND_SLOTS {
+            assert_eq!(queue.submit(Command::DumpStatus, 0), Some(index));
+        }
+        assert_eq!(queue.submit(Command::DumpStatus, 0), None);
+        assert_eq!(
+            queue.send(Command::Unload, 0, Duration::ZERO),
+            Err("Every command slot is busy".to_string())
+        );
+    }
+
+    #[test]
+    fn commands_run_oldest_first_and_free_their_slot() {
+        let queue = zeroed::<CommandQueue>();
+        let first = queue.submit(Command::DisableHook, 3).unwrap();
+        let second = queue.submit(Command::DumpStatus, 0).unwrap();
+
+        let (index, command, argument) = queue.next_pending().unwrap();
+        assert_eq!(
+            (index, command, argument),
+            (first, Command::DisableHook as u32, 3)
+        );
+        assert_eq!(queue.take_reply(first), None);
+        queue.complete(first, CommandResult::NotInstalled, 0);
+        assert_eq!(
+            queue.take_reply(first),
+            Some(CommandReply {
+                result: CommandResult::NotInstalled as i32,
+                value: 0,
+            })
+        );
+        assert_eq!(queue.take_reply(first), None);
+
+        // The slot is free again, while the second command still waits
+        assert_eq!(queue.submit(Command::Unload, 0), Some(first));
+        let (index, command, _) = queue.next_pending().unwrap();
+        assert_eq!((index, command), (second, Command::DumpStatus as u32));
+    }
+
+    #[test]
+    fn unanswered_commands_time_out_and_free_their_slot() {
+        let queue = zeroed::<CommandQueue>();
+        assert_eq!(
+            queue.send(Command::DumpStatus, 0, Duration::from_millis(30)),
+            Err("No reply to dump-status within 30ms".to_string())
+        );
+        assert_eq!(queue.next_pending(), None);
+
+        // A command abandoned while running frees its slot once completed
+        let index = queue.submit(Command::ReResolveOffsets, 0).unwrap();
+        queue.next_pending().unwrap();
+        queue.abandon(index);
+        assert_eq!(queue.submit(Command::DumpStatus, 0), Some(1));
+        queue.complete(index, CommandResult::Ok, 0);
+        assert_eq!(queue.take_reply(index), None);
+        assert_eq!(queue.submit(Command::DumpStatus, 0), Some(index));
+    }
+
+    fn messages(drained: &DrainedLog) -> Vec<&str> {
+        drained.entries.iter().map(|e| e.message.as_str()).collect()
+    }
+
+    #[test]
+    fn the_log_drains_in_order_across_the_end_of_the_ring() {
+        let ring = zeroed::<LogRing>();
+        let mut cursor = 0;
+        for i in 0..LOG_RECORDS - 2 {
+            ring.write(LogLevel::Debug, &i.to_string());
+        }
+        assert_eq!(ring.drain(&mut cursor).entries.len(), LOG_RECORDS - 2);
+
+        for i in 0..5 {
+            ring.write(LogLevel::Warn, &format!("wrapped {i}"));
+        }
+        let drained = ring.drain(&mut cursor);
+        assert_eq!(drained.lost, 0);
+        assert_eq!(
+            messages(&drained),
+            [
+                "wrapped 0",
+                "wrapped 1",
+                "wrapped 2",
+                "wrapped 3",
+                "wrapped 4"
+            ]
+        );
+        assert_eq!(drained.entries[0].level, LogLevel::Warn);
+        assert_eq!(cursor, LOG_RECORDS as u64 + 3);
+        assert!(ring.drain(&mut cursor).entries.is_empty());
+    }
+
+    #[test]
+    fn records_overwritten_before_the_drain_are_counted_as_lost() {
+        let ring = zeroed::<LogRing>();
+        let mut cursor = 0;
+        for i in 0..LOG_RECORDS + 10 {
+            ring.write(LogLevel::Info, &i.to_string());
+        }
+        let drained = ring.drain(&mut cursor);
+        assert_eq!(drained.lost, 10);
+        assert_eq!(drained.entries.len(), LOG_RECORDS);
+        assert_eq!(drained.entries[0].message, "10");
+        assert_eq!(cursor, LOG_RECORDS as u64 + 10);
+
+        // A record overwritten while it was due to be read
+        ring.write(LogLevel::Info, "read");
+        ring.records[(cursor % LOG_RECORDS as u64) as usize]
+            .sequence
+            .fetch_add(2 * LOG_RECORDS as u64, Ordering::Relaxed);
+        ring.write(LogLevel::Info, "next");
+        let drained = ring.drain(&mut cursor);
+        assert_eq!(drained.lost, 1);
+        assert_eq!(messages(&drained), ["next"]);
+    }
+
+    #[test]
+    fn long_messages_are_cut_at_a_character_boundary() {
+        let ring = zeroed::<LogRing>();
+        let message = "é".repeat(LOG_MESSAGE_LEN);
+        ring.write(LogLevel::Error, &message);
+        let drained = ring.drain(&mut 0);
+        assert_eq!(drained.entries[0].message, "é".repeat(LOG_MESSAGE_LEN / 2));
+    }
+}
@@ -0,0 +1,53 @@
+//! Creating and opening the environment mapping. The launcher creates it for
+//! the game process it started; the DLL, or the simulator standing in for it,
+//! opens it from inside that process.
+
+use crate::{IslandEnvironment, IslandHeader, shared_memory_name};
+use std::mem::size_of;
+
+#[cfg(unix)]
+mod posix;
+#[cfg(unix)]
+use posix as backend;
+#[cfg(windows)]
+mod win32;
+#[cfg(windows)]
+use win32 as backend;
+
+/// The environment mapping of one game process, unmapped on drop.
+pub struct SharedEnvironment {
+    mapping: backend::Mapping,
+}
+
+impl SharedEnvironment {
+    /// Create the zero-filled environment for game process `pid`. Only the
+    /// current user can open it. A mapping that already exists under the name
+    /// is refused, since whoever created it chose its security.
+    pub fn create(pid: u32) -> Result<Self, String> {
+        let mapping =
+            backend::Mapping::create(&shared_memory_name(pid), size_of::<IslandEnvironment>())?;
+        Ok(Self { mapping })
+    }
+
+    /// Open the environment of game process `pid`, refusing one that is not
+    /// owned by the current user. An environment of another protocol version
+    /// may be smaller, so only the header may be read until it validates.
+    pub fn open(pid: u32) -> Result<Self, String> {
+        let mapping = backend::Mapping::open(&shared_memory_name(pid), size_of::<IslandHeader>())?;
+        Ok(Self { mapping })
+    }
+
+    pub fn as_ptr(&self) -> *mut IslandEnvironment {
+        self.mapping.as_ptr() as *mut IslandEnvironment
+    }
+
+    pub fn environment(&self) -> &IslandEnvironment {
+        unsafe { &*self.as_ptr() }
+    }
+
+    /// Unmap without removing the environment, which stays with the game
+    /// process that has it open so that a later launcher can reattach.
+    pub fn detach(self) {
+        self.mapping.detach();
+    }
+}
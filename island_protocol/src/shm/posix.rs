@@ -0,0 +1,116 @@
+//! POSIX shared memory objects, created with mode 0600.
+
+use std::ffi::{CString, c_void};
+use std::io;
+use std::mem;
+use std::ptr;
+
+pub struct Mapping {
+    fd: i32,
+    view: *mut c_void,
+    size: usize,
+    /// Set for the creator, which removes the name on drop.
+    owned_name: Option<CString>,
+}
+
+impl Mapping {
+    pub fn create(name: &str, size: usize) -> Result<Self, String> {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            let fd = libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o600 as libc::mode_t,
+            );
+            if fd < 0 {
+                return Err(format!("shm_open failed: {}", io::Error::last_os_error()));
+            }
+            let mapping = Self {
+                fd,
+                view: ptr::null_mut(),
+                size,
+                owned_name: Some(name),
+            };
+            if libc::ftruncate(fd, size as libc::off_t) != 0 {
+                return Err(format!("ftruncate failed: {}", io::Error::last_os_error()));
+            }
+            mapping.map()
+        }
+    }
+
+    /// Map the whole object, which must hold at least `min_size` bytes.
+    pub fn open(name: &str, min_size: usize) -> Result<Self, String> {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_RDWR, 0);
+            if fd < 0 {
+                return Err(format!("shm_open failed: {}", io::Error::last_os_error()));
+            }
+            let mut mapping = Self {
+                fd,
+                view: ptr::null_mut(),
+                size: 0,
+                owned_name: None,
+            };
+            let mut stat: libc::stat = mem::zeroed();
+            if libc::fstat(fd, &mut stat) != 0 {
+                return Err(format!("fstat failed: {}", io::Error::last_os_error()));
+            }
+            if stat.st_uid != libc::geteuid() {
+                return Err(format!(
+                    "The mapping belongs to user {}, not the current user",
+                    stat.st_uid
+                ));
+            }
+            if (stat.st_size as usize) < min_size {
+                return Err(format!(
+                    "The mapping holds {} bytes, at least {min_size} are needed",
+                    stat.st_size
+                ));
+            }
+            mapping.size = stat.st_size as usize;
+            mapping.map()
+        }
+    }
+
+    fn map(mut self) -> Result<Self, String> {
+        let view = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                self.size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.fd,
+                0,
+            )
+        };
+        if view == libc::MAP_FAILED {
+            return Err(format!("mmap failed: {}", io::Error::last_os_error()));
+        }
+        self.view = view;
+        Ok(self)
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.view as *mut u8
+    }
+
+    /// Unmap but leave the name, so the object outlives its creator.
+    pub fn detach(mut self) {
+        self.owned_name = None;
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.view.is_null() {
+                libc::munmap(self.view, self.size);
+            }
+            libc::close(self.fd);
+            if let Some(name) = &self.owned_name {
+                libc::shm_unlink(name.as_ptr());
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Win32 named file mappings, owned by and only open to the current user.
+
+use std::ffi::{CStr, CString, c_void};
+use std::mem;
+use std::ptr;
+use windows_sys::Win32::Foundation::*;
+use windows_sys::Win32::Security::Authorization::{
+    ConvertSidToStringSidA, ConvertStringSecurityDescriptorToSecurityDescriptorA, GetSecurityInfo,
+    SDDL_REVISION_1, SE_KERNEL_OBJECT,
+};
+use windows_sys::Win32::Security::{
+    EqualSid, GetTokenInformation, OWNER_SECURITY_INFORMATION, PSID, SECURITY_ATTRIBUTES,
+    TOKEN_QUERY, TOKEN_USER, TokenUser,
+};
+use windows_sys::Win32::Storage::FileSystem::READ_CONTROL;
+use windows_sys::Win32::System::Memory::*;
+use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+pub struct Mapping {
+    handle: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+}
+
+impl Mapping {
+    pub fn create(name: &str, size: usize) -> Result<Self, String> {
+        let name = CString::new(name).unwrap();
+        let mut user = [0u64; 64];
+        unsafe {
+            let mut sid = ptr::null_mut();
+            if ConvertSidToStringSidA(current_user(&mut user)?, &mut sid) == 0 {
+                return Err(format!("ConvertSidToStringSidA failed: {}", GetLastError()));
+            }
+            let sid_string = CStr::from_ptr(sid as *const i8)
+                .to_string_lossy()
+                .into_owned();
+            LocalFree(sid as HLOCAL);
+
+            // The game runs as the same user and gets no inherited handle
+            let sddl = CString::new(format!("O:{sid_string}D:P(A;;GA;;;{sid_string})")).unwrap();
+            let mut descriptor = ptr::null_mut();
+            if ConvertStringSecurityDescriptorToSecurityDescriptorA(
+                sddl.as_ptr() as *const u8,
+                SDDL_REVISION_1,
+                &mut descriptor,
+                ptr::null_mut(),
+            ) == 0
+            {
+                return Err(format!(
+                    "ConvertStringSecurityDescriptorToSecurityDescriptorA failed: {}",
+                    GetLastError()
+                ));
+            }
+            let sa = SECURITY_ATTRIBUTES {
+                nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: descriptor,
+                bInheritHandle: FALSE,
+            };
+            let handle = CreateFileMappingA(
+                INVALID_HANDLE_VALUE,
+                &sa,
+                PAGE_READWRITE,
+                0,
+                size as u32,
+                name.as_ptr() as *const u8,
+            );
+            let error = GetLastError();
+            LocalFree(descriptor as HLOCAL);
+            if handle.is_null() {
+                return Err(format!("CreateFileMappingA failed: {error}"));
+            }
+            if error == ERROR_ALREADY_EXISTS {
+                CloseHandle(handle);
+                return Err("A mapping with this name already exists".to_string());
+            }
+            Self::map(handle, size)
+        }
+    }
+
+    /// Map the whole mapping, which must hold at least `min_size` bytes.
+    pub fn open(name: &str, min_size: usize) -> Result<Self, String> {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            let handle = OpenFileMappingA(
+                FILE_MAP_READ | FILE_MAP_WRITE | READ_CONTROL,
+                FALSE,
+                name.as_ptr() as *const u8,
+            );
+            if handle.is_null() {
+                return Err(format!("OpenFileMappingA failed: {}", GetLastError()));
+            }
+            // Any other owner could have planted the mapping to feed us hostile offsets
+            match owned_by_current_user(handle) {
+                Ok(true) => {
+                    let mapping = Self::map(handle, 0)?;
+                    let mut info: MEMORY_BASIC_INFORMATION = mem::zeroed();
+                    VirtualQuery(
+                        mapping.view.Value,
+                        &mut info,
+                        mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+                    );
+                    if info.RegionSize < min_size {
+                        return Err(format!(
+                            "The mapping holds {} bytes, at least {min_size} are needed",
+                            info.RegionSize
+                        ));
+                    }
+                    Ok(mapping)
+                }
+                Ok(false) => {
+                    CloseHandle(handle);
+                    Err("The mapping is not owned by the current user".to_string())
+                }
+                Err(e) => {
+                    CloseHandle(handle);
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    unsafe fn map(handle: HANDLE, size: usize) -> Result<Self, String> {
+        unsafe {
+            let view = MapViewOfFile(handle, FILE_MAP_READ | FILE_MAP_WRITE, 0, 0, size);
+            if view.Value.is_null() {
+                let error = GetLastError();
+                CloseHandle(handle);
+                return Err(format!("MapViewOfFile failed: {error}"));
+            }
+            Ok(Self { handle, view })
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.view.Value as *mut u8
+    }
+
+    /// The object lives while any handle to it is open, and the game keeps one.
+    pub fn detach(self) {}
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            UnmapViewOfFile(self.view);
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+/// The SID of the user running this process. `buffer` receives the
+/// TOKEN_USER and the SID it points to.
+fn current_user(buffer: &mut [u64; 64]) -> Result<PSID, String> {
+    unsafe {
+        let mut token = ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return Err(format!("OpenProcessToken failed: {}", GetLastError()));
+        }
+        let mut len = 0u32;
+        let ok = GetTokenInformation(
+            token,
+            TokenUser,
+            buffer.as_mut_ptr() as *mut c_void,
+            mem::size_of_val(buffer) as u32,
+            &mut len,
+        );
+        let error = GetLastError();
+        CloseHandle(token);
+        if ok == 0 {
+            return Err(format!("GetTokenInformation failed: {error}"));
+        }
+        Ok((*(buffer.as_ptr() as *const TOKEN_USER)).User.Sid)
+    }
+}
+
+fn owned_by_current_user(handle: HANDLE) -> Result<bool, String> {
+    let mut user = [0u64; 64];
+    let user = current_user(&mut user)?;
+    unsafe {
+        let mut owner = ptr::null_mut();
+        let mut descriptor = ptr::null_mut();
+        let error = GetSecurityInfo(
+            handle,
+            SE_KERNEL_OBJECT,
+            OWNER_SECURITY_INFORMATION,
+            &mut owner,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut descriptor,
+        );
+        if error != ERROR_SUCCESS {
+            return Err(format!("GetSecurityInfo failed: {error}"));
+        }
+        let owned = EqualSid(owner, user) != 0;
+        LocalFree(descriptor as HLOCAL);
+        Ok(owned)
+    }
+}
@@ -0,0 +1,513 @@
+//! Stands in for the game with hutao_minhook injected, so the launcher ↔ DLL
+//! protocol can be exercised without Windows or the game.
+//!
+//! `island_simulator serve` plays the DLL inside a fake game process. `island_simulator
+//! round-trip` plays the launcher: it starts `serve`, publishes settings and sends
+//! commands, and checks what comes back.
+
+use island_protocol::endpoints;
+use island_protocol::hooks::{self, HookBackend};
+use island_protocol::{
+    Command, CommandResult, EffectiveValues, Feature, FunctionOffsets, HookStatus,
+    IslandEnvironment, IslandHeader, IslandState, LogLevel, MAX_FEATURES, ModuleOffset,
+    PROLOGUE_MISMATCH, PROTOCOL_VERSION, Prologue, Settings, SharedEnvironment,
+};
+use std::io::{self, BufRead, Write};
+use std::process::{self, Stdio};
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: island_simulator <COMMAND>
+
+Commands:
+  serve
+      Act as the game with the DLL injected. Waits for a line on standard input,
+      which stands for the injection, then opens the environment named after this
+      process, reports its hooks, serves commands and runs the endpoint logic
+      against the published settings until it is stopped or unloaded.
+  round-trip
+      Act as the launcher for a `serve` child process and check settings, status,
+      log records and commands end to end. Exits with code 1 on the first failure.";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The DLL beats once a second.
+const HEARTBEAT_TICKS: u32 = 10;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How long unloading waits for the game to get its own settings back.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Field of view the simulated game sets every frame: a normal scene.
+const GAME_FIELD_OF_VIEW: f32 = 45.0;
+/// Every function of the simulated game starts with these bytes.
+const GAME_CODE: [u8; 8] = [0x48, 0x89, 0x5C, 0x24, 0x08, 0x57, 0x48, 0x83];
+
+fn main() {
+    let result = match std::env::args().nth(1).as_deref() {
+        Some("serve") => serve(),
+        Some("round-trip") => round_trip(),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        process::exit(1);
+    }
+}
+
+/// The simulated game lets the team page open directly whenever asked.
+fn game_can_enter_team_page() -> bool {
+    true
+}
+
+fn field_of_view_record(settings: &Settings) -> String {
+    format!(
+        "set_field_of_view: {:?}",
+        endpoints::field_of_view(settings, GAME_FIELD_OF_VIEW)
+    )
+}
+
+fn open_team_record(settings: &Settings) -> String {
+    format!(
+        "open_team: {:?}",
+        endpoints::open_team(settings, game_can_enter_team_page)
+    )
+}
+
+fn serve() -> Result<(), String> {
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| format!("Cannot read standard input: {e}"))?;
+
+    let island = SharedEnvironment::open(process::id())?;
+    let ptr = island.as_ptr();
+    // Only the header is known to be there until it has been checked
+    let header = unsafe { &mut (*ptr).header };
+    if let Err(error) = header.validate() {
+        header.error = error as u32;
+        header.state = IslandState::Error as u32;
+        header.dll_version = PROTOCOL_VERSION;
+        return Err(format!("Incompatible environment: {}", error.name()));
+    }
+    header.state = IslandState::Started as u32;
+    header.dll_version = PROTOCOL_VERSION;
+
+    let env = unsafe { &*ptr };
+    env.log.write(
+        LogLevel::Info,
+        &format!("island_simulator attached, protocol {PROTOCOL_VERSION}"),
+    );
+    let mut backend = SimulatedHooks::default();
+    let (mut settings_generation, mut settings) = env.settings.read().unwrap_or_default();
+    hooks::sync_hooks(env, &mut backend, &settings, true);
+
+    let mut last_records: [Option<String>; 3] = Default::default();
+    let mut ticks = 0u32;
+    let mut unload = None;
+    let mut settling = false;
+    while unsafe { ptr::read_volatile(&raw const (*ptr).header.state) }
+        != IslandState::Stopped as u32
+    {
+        unload = hooks::serve_commands(env, &mut backend, &settings);
+        if unload.is_some() {
+            break;
+        }
+
+        // Hook or unhook features as the launcher changes the settings
+        let mut changed = false;
+        if let Some((generation, published)) = env.settings.read()
+            && generation != settings_generation
+        {
+            (settings_generation, settings) = (generation, published);
+            changed = true;
+        }
+        if changed || settling {
+            settling = hooks::sync_hooks(env, &mut backend, &settings, false);
+        }
+
+        game_frame(env, &settings, &mut last_records);
+
+        if ticks.is_multiple_of(HEARTBEAT_TICKS) {
+            env.hook_report.heartbeat.fetch_add(1, Ordering::Release);
+        }
+        ticks = ticks.wrapping_add(1);
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    env.log
+        .write(LogLevel::Info, "Removing hooks and unloading");
+    if let Some((_, published)) = env.settings.read() {
+        settings = published;
+    }
+    hooks::settle_hooks(env, &mut backend, &settings, SETTLE_TIMEOUT, || {
+        game_frame(env, &settings, &mut last_records);
+        thread::sleep(POLL_INTERVAL);
+    });
+    unsafe { ptr::write_volatile(&raw mut (*ptr).header.state, IslandState::Stopped as u32) };
+    if let Some(slot) = unload {
+        env.commands.complete(slot, CommandResult::Ok, 0);
+    }
+    Ok(())
+}
+
+/// One game frame: every hooked function is called once.
+fn game_frame(
+    env: &IslandEnvironment,
+    settings: &Settings,
+    last_records: &mut [Option<String>; 3],
+) {
+    for (feature, last) in Feature::ALL.into_iter().zip(last_records) {
+        if env.hook_report.status(feature) != HookStatus::Installed {
+            continue;
+        }
+        let record = match feature {
+            Feature::FieldOfView => {
+                let calls = endpoints::field_of_view(settings, GAME_FIELD_OF_VIEW);
+                env.effective.write(&calls.effective(GAME_FIELD_OF_VIEW));
+                field_of_view_record(settings)
+            }
+            Feature::TeamProgress => open_team_record(settings),
+            Feature::CraftRedirect => format!(
+                "craft_entry: redirect {}",
+                endpoints::redirect_craft_entry(settings)
+            ),
+        };
+        // Only changes are logged, the ring is small
+        if last.as_ref() != Some(&record) {
+            env.log.write(LogLevel::Debug, &record);
+            *last = Some(record);
+        }
+    }
+}
+
+/// Pretends to hook the simulated game, where every non-zero offset is a
+/// function and every function starts with `GAME_CODE`.
+#[derive(Default)]
+struct SimulatedHooks {
+    created: [bool; MAX_FEATURES],
+}
+
+impl HookBackend for SimulatedHooks {
+    fn resolve(&self, _env: &IslandEnvironment, offset: ModuleOffset) -> Option<usize> {
+        (offset.rva != 0).then_some(offset.rva as usize)
+    }
+
+    fn read_code(&self, _address: usize, code: &mut [u8]) -> bool {
+        // Padding after the first instructions
+        code.fill(0xCC);
+        let len = code.len().min(GAME_CODE.len());
+        code[..len].copy_from_slice(&GAME_CODE[..len]);
+        true
+    }
+
+    fn create(
+        &mut self,
+        _env: &IslandEnvironment,
+        feature: Feature,
+        _target: usize,
+    ) -> Result<(), i32> {
+        self.created[feature as usize] = true;
+        Ok(())
+    }
+
+    fn created(&self, feature: Feature) -> bool {
+        self.created[feature as usize]
+    }
+
+    fn switch(&mut self, _feature: Feature, _on: bool) -> Result<(), i32> {
+        Ok(())
+    }
+
+    fn error_name(&self, _code: i32) -> &'static str {
+        "simulated error"
+    }
+}
+
+fn round_trip() -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Cannot locate myself: {e}"))?;
+    let mut child = process::Command::new(exe)
+        .arg("serve")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Cannot start serve: {e}"))?;
+
+    let result = drive(&mut child);
+    if result.is_err() {
+        let _ = child.kill();
+    }
+    let status = child
+        .wait()
+        .map_err(|e| format!("Cannot wait for serve: {e}"))?;
+    result?;
+    if !status.success() {
+        return Err(format!("serve exited with {status}"));
+    }
+    println!("Round trip passed");
+    Ok(())
+}
+
+/// The launcher's side of one game session.
+fn drive(child: &mut process::Child) -> Result<(), String> {
+    let island = SharedEnvironment::create(child.id())?;
+    let ptr = island.as_ptr();
+    unsafe {
+        (*ptr).header = IslandHeader::new();
+        // Any non-zero offset counts as found; craft_entry stays missing
+        let prologue = Prologue::from_pattern(&[
+            Some(0x48),
+            Some(0x89),
+            Some(0x5C),
+            Some(0x24),
+            None,
+            Some(0x57),
+        ]);
+        for (index, name) in FunctionOffsets::FIELD_NAMES.into_iter().enumerate() {
+            if name != "craft_entry" {
+                let offsets = &mut (*ptr).function_offsets;
+                offsets.get_mut(name).unwrap().rva = 0x1000 * (index as u64 + 1);
+                *offsets.prologue_mut(name).unwrap() = prologue;
+            }
+        }
+    }
+    let env = island.environment();
+    let mut settings = Settings {
+        field_of_view: 90.0,
+        disable_fog: true,
+        target_frame_rate: 120,
+        ..Default::default()
+    };
+    env.settings.write(&settings);
+
+    // Inject
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin).map_err(|e| format!("Cannot inject: {e}"))?;
+
+    let header = wait_for("the simulated DLL to start", || {
+        let header = unsafe { ptr::read_volatile(&raw const (*ptr).header) };
+        (header.dll_version != 0).then_some(header)
+    })?;
+    if header.state() != Some(IslandState::Started) {
+        return Err(format!(
+            "The simulated DLL reported state {}, error {}",
+            header.state, header.error
+        ));
+    }
+    println!("ok  header accepted");
+
+    let report = &env.hook_report;
+    let expected = [
+        (Feature::FieldOfView, HookStatus::Installed),
+        (Feature::TeamProgress, HookStatus::Inactive),
+        (Feature::CraftRedirect, HookStatus::Skipped),
+    ];
+    for (feature, status) in expected {
+        if report.status(feature) != status {
+            return Err(format!(
+                "{} is {:?}, expected {status:?}",
+                feature.key(),
+                report.status(feature)
+            ));
+        }
+    }
+    println!("ok  hook report");
+
+    let mut cursor = 0;
+    expect_records(env, &mut cursor, &[field_of_view_record(&settings)])?;
+    settings.field_of_view = 60.0;
+    settings.disable_fog = false;
+    settings.remove_open_team_progress = true;
+    env.settings.write(&settings);
+    expect_records(
+        env,
+        &mut cursor,
+        &[field_of_view_record(&settings), open_team_record(&settings)],
+    )?;
+    println!("ok  settings reached the endpoints");
+
+    // Team progress was hooked when its setting came on; it follows the toggle
+    let team_status =
+        |status: HookStatus| move || (report.status(Feature::TeamProgress) == status).then_some(());
+    wait_for(
+        "team_progress to be hooked",
+        team_status(HookStatus::Installed),
+    )?;
+    settings.remove_open_team_progress = false;
+    env.settings.write(&settings);
+    wait_for(
+        "team_progress to be unhooked",
+        team_status(HookStatus::Inactive),
+    )?;
+    settings.remove_open_team_progress = true;
+    env.settings.write(&settings);
+    wait_for(
+        "team_progress to be hooked again",
+        team_status(HookStatus::Installed),
+    )?;
+    println!("ok  hooks follow the settings");
+
+    let expected = EffectiveValues {
+        original_field_of_view: GAME_FIELD_OF_VIEW,
+        field_of_view: settings.field_of_view,
+        enable_fog: !settings.disable_fog,
+        target_frame_rate: settings.target_frame_rate,
+        low_fov_scene: false,
+    };
+    match env.effective.read() {
+        Some((generation, values)) if generation > 0 && values == expected => {}
+        other => return Err(format!("Effective values {other:?}, expected {expected:?}")),
+    }
+    println!("ok  effective values");
+
+    // Back to the game's own field of view, frame rate and fog: the hook stays
+    // until a call has handed the game its defaults, then turns off
+    let tuned = settings;
+    settings = Settings {
+        remove_open_team_progress: true,
+        ..Default::default()
+    };
+    env.settings.write(&settings);
+    let fov_status =
+        |status: HookStatus| move || (report.status(Feature::FieldOfView) == status).then_some(());
+    wait_for(
+        "field_of_view to be unhooked",
+        fov_status(HookStatus::Inactive),
+    )?;
+    let defaults = endpoints::field_of_view(&settings, GAME_FIELD_OF_VIEW);
+    match env.effective.read() {
+        Some((_, values)) if values == defaults.effective(GAME_FIELD_OF_VIEW) => {}
+        other => return Err(format!("Effective values {other:?} after a reset")),
+    }
+    settings = tuned;
+    env.settings.write(&settings);
+    wait_for(
+        "field_of_view to be hooked again",
+        fov_status(HookStatus::Installed),
+    )?;
+    println!("ok  reset restores the game's values");
+
+    let beat = report.heartbeat.load(Ordering::Acquire);
+    wait_for("a heartbeat", || {
+        (report.heartbeat.load(Ordering::Acquire) > beat).then_some(())
+    })?;
+    println!("ok  heartbeat");
+
+    let send = |command: Command, argument: u32| {
+        env.commands
+            .send(command, argument, TIMEOUT)
+            .map(|reply| (CommandResult::from_code(reply.result), reply.value))
+    };
+    let replies = [
+        (
+            send(Command::DisableHook, Feature::FieldOfView as u32)?,
+            (Some(CommandResult::Ok), 0),
+        ),
+        (
+            send(Command::DisableHook, Feature::CraftRedirect as u32)?,
+            (Some(CommandResult::NotInstalled), 0),
+        ),
+        (
+            send(Command::DumpStatus, 0)?,
+            (Some(CommandResult::Ok), Feature::TeamProgress.bit()),
+        ),
+    ];
+    for (reply, expected) in replies {
+        if reply != expected {
+            return Err(format!("Command replied {reply:?}, expected {expected:?}"));
+        }
+    }
+    if report.status(Feature::FieldOfView) != HookStatus::Disabled {
+        return Err("disable-hook did not disable field_of_view".to_string());
+    }
+    println!("ok  commands");
+
+    // A launcher that exits detached leaves the environment to the game
+    island.detach();
+    let island = SharedEnvironment::open(child.id())?;
+    let ptr = island.as_ptr();
+    // craft_entry turns up, but the bytes expected there are not the game's
+    unsafe {
+        let offsets = &mut (*ptr).function_offsets;
+        offsets.get_mut("craft_entry").unwrap().rva = 0x8000;
+        *offsets.prologue_mut("craft_entry").unwrap() = Prologue::from_pattern(&[Some(0xE9)]);
+    }
+    let env = island.environment();
+    env.header
+        .validate()
+        .map_err(|e| format!("Reattached header: {}", e.name()))?;
+    let state = unsafe { ptr::read_volatile(&raw const (*ptr).header.state) };
+    if IslandState::from_code(state) != Some(IslandState::Started) {
+        return Err(format!("The simulated DLL is {state:?} after detaching"));
+    }
+    match env.settings.read() {
+        Some((_, current)) if current == settings => {}
+        other => {
+            return Err(format!(
+                "Settings {other:?} after detaching, expected {settings:?}"
+            ));
+        }
+    }
+    println!("ok  reattach");
+
+    settings.redirect_craft_entry = true;
+    env.settings.write(&settings);
+    let reply = env.commands.send(Command::ReResolveOffsets, 0, TIMEOUT)?;
+    if CommandResult::from_code(reply.result) != Some(CommandResult::Ok) {
+        return Err(format!("re-resolve replied {}", reply.result));
+    }
+    let report = &env.hook_report;
+    if report.status(Feature::CraftRedirect) != HookStatus::Failed
+        || report.minhook_status(Feature::CraftRedirect) != PROLOGUE_MISMATCH
+    {
+        return Err(format!(
+            "craft_redirect is {:?} ({}), expected a prologue mismatch",
+            report.status(Feature::CraftRedirect),
+            report.minhook_status(Feature::CraftRedirect)
+        ));
+    }
+    if report.status(Feature::TeamProgress) != HookStatus::Installed {
+        return Err("team_progress stopped after the prologue mismatch".to_string());
+    }
+    println!("ok  prologue check");
+
+    let reply = env.commands.send(Command::Unload, 0, TIMEOUT)?;
+    let result = CommandResult::from_code(reply.result);
+    if result != Some(CommandResult::Ok) {
+        return Err(format!("unload replied {result:?}"));
+    }
+    wait_for("the simulated DLL to stop", || {
+        let state = unsafe { ptr::read_volatile(&raw const (*ptr).header.state) };
+        (IslandState::from_code(state) == Some(IslandState::Stopped)).then_some(())
+    })?;
+    println!("ok  unload");
+    Ok(())
+}
+
+fn wait_for<T>(what: &str, mut poll: impl FnMut() -> Option<T>) -> Result<T, String> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(value) = poll() {
+            return Ok(value);
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Timed out waiting for {what}"));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Wait until a log record with each of these messages has arrived.
+fn expect_records(
+    env: &IslandEnvironment,
+    cursor: &mut u64,
+    messages: &[String],
+) -> Result<(), String> {
+    let mut pending: Vec<&String> = messages.iter().collect();
+    wait_for(&format!("log records {pending:?}"), || {
+        for entry in env.log.drain(cursor).entries {
+            pending.retain(|message| **message != entry.message);
+        }
+        pending.is_empty().then_some(())
+    })
+}
@@ -0,0 +1,490 @@
+use crate::client_switch::ClientSwitch;
+use crate::hutao_config::{Command, Feature, FunctionOffsets};
+use crate::il2cpp_import::{NameTable, load_methods, map_offsets};
+use crate::island_client::{feature_status, find_game, open_environment, send_command};
+use crate::island_format;
+use crate::offset_checker::check_offsets;
+use crate::offset_database::{FunctionAddress, OffsetDatabase, OffsetEntry, OffsetSet};
+use crate::offset_feed::{self, Installation, MergeOutcome};
+use crate::pe_reader::PeFile;
+use crate::signature_generator::diff_builds;
+use crate::signature_scanner::Signature;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "\
+Usage: gi-toolkit [COMMAND]
+
+Without a command the launcher window is opened.
+
+Commands:
+  import-il2cpp <script.json|dump.cs> --client <client> --version <game_version>
+                [--names <il2cpp_names.json>] [--exe <game.exe>] [--database <offsets.json>]
+      Map Il2CppDumper output to offsets and write an offset database entry.
+  check-offsets <game.exe> [--client <client>] [--version <game_version>] [--database <offsets.json>]
+      Check that each offset is a plausible function start in the executable.
+      Client and version default to the installation the executable belongs to.
+      When every offset passes, functions without a prologue get the bytes they start
+      with in this executable, unless the entry was made for another build.
+      Exits with code 1 if any offset fails or does not start with its prologue.
+  import-island <offsets.json> --version <game_version> [--database <offsets.json>]
+      Import offsets published in the UnlockerIsland / Snap.Hutao layout.
+  export-island <offsets.json> --version <game_version> [--database <offsets.json>]
+      Export offsets for a game version in the UnlockerIsland / Snap.Hutao layout.
+  sigdiff <old.exe> <new.exe> --client <client> --version <old_version> --new-version <new_version>
+          [--database <offsets.json>]
+      Generate signatures for the known functions of the old build, locate them in the
+      new build and write an entry for it. Functions that cannot be matched confidently
+      are left out of the entry and the command exits with code 1. Such a partial entry
+      is only written when there is no entry for the new version yet.
+  update-offsets [--source <url>] [--exe <game.exe>] [--database <offsets.json>]
+      Fetch the signed offset feed (https:// or file://, default from assets/offset_feed.txt),
+      verify it and merge it into the offset database. With --exe, entries for that build
+      must also pass check-offsets. Rejected entries never replace local ones and make
+      the command exit with code 1.
+  island-command <re-resolve|disable-hook|unload|dump-status> [--feature <feature>]
+                 [--pid <pid> | --exe <game.exe>]
+      Send a command to the DLL of a running game and wait for its acknowledgement.
+      disable-hook needs --feature (field_of_view, team_progress or craft_redirect).
+      The game is picked by process ID or executable path; either may be left out
+      while only one game is running.
+      Exits with code 1 if the DLL reports a failure.";
+
+/// Positional arguments and `--name value` options of one command.
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("Missing value for --{name}"))?;
+                options.insert(name.to_string(), value.clone());
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+        Ok(Self {
+            positional,
+            options,
+        })
+    }
+
+    fn positional(&self, index: usize, what: &str) -> Result<&str, String> {
+        self.positional
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| format!("Missing {what}"))
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> Result<&str, String> {
+        self.option(name).ok_or_else(|| format!("Missing --{name}"))
+    }
+
+    fn database_path(&self) -> PathBuf {
+        self.option("database")
+            .map(PathBuf::from)
+            .unwrap_or_else(OffsetDatabase::default_path)
+    }
+}
+
+/// Run a command-line command and return the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let (command, rest) = args.split_first().expect("run requires a command");
+    let result = Args::parse(rest).and_then(|args| match command.as_str() {
+        "import-il2cpp" => import_il2cpp(&args),
+        "check-offsets" => check_offsets_command(&args),
+        "import-island" => import_island(&args),
+        "export-island" => export_island(&args),
+        "sigdiff" => sigdiff(&args),
+        "update-offsets" => update_offsets(&args),
+        "island-command" => island_command(&args),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            Ok(0)
+        }
+        _ => Err(format!("Unknown command '{command}'\n\n{USAGE}")),
+    });
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{e}");
+            2
+        }
+    }
+}
+
+fn import_il2cpp(args: &Args) -> Result<i32, String> {
+    let client = args.required("client")?;
+    let game_version = args.required("version")?;
+    let methods = load_methods(Path::new(args.positional(0, "Il2CppDumper output file")?))?;
+    let names = args
+        .option("names")
+        .map(PathBuf::from)
+        .unwrap_or_else(NameTable::default_path);
+    let imported = map_offsets(&methods, &NameTable::load(&names)?);
+    let build = args
+        .option("exe")
+        .map(|exe| PeFile::open(Path::new(exe)).map(|pe| pe.fingerprint()))
+        .transpose()?;
+
+    let path = args.database_path();
+    let mut database = OffsetDatabase::load_or_default(&path)?;
+    database.upsert(OffsetEntry {
+        client: client.to_string(),
+        game_version: game_version.to_string(),
+        build,
+        offsets: imported.offsets,
+        signatures: BTreeMap::new(),
+        prologues: BTreeMap::new(),
+    });
+    database.save(&path)?;
+
+    let total = FunctionOffsets::FIELD_NAMES.len();
+    println!(
+        "Resolved {} of {total} offsets, written to {}",
+        total - imported.unresolved.len(),
+        path.display()
+    );
+    for (field, reason) in &imported.unresolved {
+        println!("  unresolved {field}: {reason}");
+    }
+    Ok(if imported.unresolved.is_empty() { 0 } else { 1 })
+}
+
+fn check_offsets_command(args: &Args) -> Result<i32, String> {
+    let exe = args.positional(0, "game executable")?;
+    let mut switcher = ClientSwitch {
+        game_path: exe.to_string(),
+        ..Default::default()
+    };
+    switcher.sync_with_installation();
+    let client = args
+        .option("client")
+        .unwrap_or(switcher.client_type.offset_key());
+    let game_version = match args.option("version") {
+        Some(version) => version.to_string(),
+        None => switcher.game_version()?,
+    };
+
+    let path = args.database_path();
+    let mut database = OffsetDatabase::load(&path)?;
+    let entry = database
+        .find(client, &game_version)
+        .ok_or_else(|| format!("No offsets for {client} build {game_version}"))?
+        .clone();
+    let image = PeFile::open(Path::new(exe))?;
+    let resolved = entry.resolve(Some(&image))?;
+
+    println!("Checking {client} {game_version} against {exe}");
+    println!("Build: {}", image.fingerprint());
+    let reports = check_offsets(&image, &resolved.offsets);
+    for report in &reports {
+        let address = report
+            .address
+            .as_ref()
+            .map_or("-".to_string(), |a| a.to_string());
+        let section = report.section.as_deref().unwrap_or("-");
+        let result = match (report.passed(), report.checked) {
+            (false, _) => "FAIL",
+            (true, false) => "SKIP",
+            (true, true) => "PASS",
+        };
+        println!(
+            "{result}  {:<28} {address:<12} {section}  {}",
+            report.field,
+            report.problems.join("; ")
+        );
+    }
+    for (feature, reason) in &resolved.disabled {
+        println!("{} disabled: {reason}", feature.name());
+    }
+
+    let failed = reports.iter().filter(|r| !r.passed()).count();
+    if failed > 0 {
+        println!("{failed} of {} offsets failed", reports.len());
+        return Ok(1);
+    }
+    println!("All {} offsets passed", reports.len());
+
+    // Prologues the entry lacks are taken from this executable, unless the
+    // entry was made for another one
+    let mut confirmed = entry.clone();
+    match confirmed.confirm_prologues(&image, &resolved) {
+        Err(e) => {
+            println!("Prologues do not match: {e}");
+            Ok(1)
+        }
+        Ok(0) => Ok(0),
+        Ok(added) => match &entry.build {
+            Some(build) if *build != image.fingerprint() => {
+                println!("{added} prologue(s) not recorded: the entry was made for {build}");
+                Ok(0)
+            }
+            _ => {
+                database.upsert(confirmed);
+                database.save(&path)?;
+                println!("Recorded {added} prologue(s) from this executable");
+                Ok(0)
+            }
+        },
+    }
+}
+
+fn import_island(args: &Args) -> Result<i32, String> {
+    let file = args.positional(0, "UnlockerIsland offsets file")?;
+    let game_version = args.required("version")?;
+    let content = std::fs::read_to_string(file).map_err(|e| format!("Cannot read {file}: {e}"))?;
+
+    let path = args.database_path();
+    let mut database = OffsetDatabase::load_or_default(&path)?;
+    let mut incomplete = false;
+    for imported in island_format::parse(&content)? {
+        let Some(client) = island_format::client_for_region(&imported.region) else {
+            println!("Skipped unknown region {}", imported.region);
+            continue;
+        };
+        println!("Imported {} as {client} {game_version}", imported.region);
+        for field in &imported.missing {
+            println!("  missing {field}");
+        }
+        incomplete |= !imported.missing.is_empty();
+        database.upsert(OffsetEntry {
+            client: client.to_string(),
+            game_version: game_version.to_string(),
+            build: None,
+            offsets: imported.offsets,
+            signatures: BTreeMap::new(),
+            prologues: BTreeMap::new(),
+        });
+    }
+    database.save(&path)?;
+    Ok(if incomplete { 1 } else { 0 })
+}
+
+fn export_island(args: &Args) -> Result<i32, String> {
+    let file = args.positional(0, "output file")?;
+    let game_version = args.required("version")?;
+    let database = OffsetDatabase::load(&args.database_path())?;
+
+    let regions: Vec<(&str, &OffsetSet)> = database
+        .entries
+        .iter()
+        .filter(|e| e.game_version == game_version)
+        .filter_map(|e| {
+            if !e.signatures.is_empty() {
+                println!(
+                    "Note: {} exports fixed offsets only, not signatures",
+                    e.client
+                );
+            }
+            island_format::region_for_client(&e.client).map(|region| (region, &e.offsets))
+        })
+        .collect();
+    if regions.is_empty() {
+        return Err(format!("No offsets for game version {game_version}"));
+    }
+    std::fs::write(file, island_format::render(&regions)?)
+        .map_err(|e| format!("Cannot write {file}: {e}"))?;
+    println!("Exported {} region(s) to {file}", regions.len());
+    Ok(0)
+}
+
+fn sigdiff(args: &Args) -> Result<i32, String> {
+    let old_exe = args.positional(0, "old game executable")?;
+    let new_exe = args.positional(1, "new game executable")?;
+    let client = args.required("client")?;
+    let old_version = args.required("version")?;
+    let new_version = args.required("new-version")?;
+
+    let path = args.database_path();
+    let mut database = OffsetDatabase::load(&path)?;
+    let old_entry = database
+        .find(client, old_version)
+        .ok_or_else(|| format!("No offsets for {client} build {old_version}"))?;
+    let old_image = PeFile::open(Path::new(old_exe))?;
+    let new_image = PeFile::open(Path::new(new_exe))?;
+    let old_offsets = old_entry.resolve(Some(&old_image))?.offsets;
+
+    let mut entry = OffsetEntry {
+        client: client.to_string(),
+        game_version: new_version.to_string(),
+        build: None,
+        offsets: Default::default(),
+        signatures: BTreeMap::new(),
+        prologues: BTreeMap::new(),
+    };
+    let mut flagged = 0;
+    for diff in diff_builds(&old_image, &new_image, &old_offsets) {
+        let new_rva = diff
+            .new_rva
+            .map_or("-".to_string(), |rva| format!("{rva:#010x}"));
+        match &diff.problem {
+            None => {
+                println!(
+                    "OK    {:<28} {:#010x} -> {new_rva}  {:.0}% similar",
+                    diff.field,
+                    diff.old_rva,
+                    diff.similarity * 100.0
+                );
+                entry.offsets.set(
+                    diff.field,
+                    FunctionAddress::in_game(diff.new_rva.unwrap() as u64),
+                );
+            }
+            Some(problem) => {
+                flagged += 1;
+                println!(
+                    "CHECK {:<28} {:#010x} -> {new_rva}  {problem}",
+                    diff.field, diff.old_rva
+                );
+            }
+        }
+        if let Some(pattern) = &diff.signature {
+            println!("      {pattern}");
+            if diff.confident() {
+                entry.signatures.insert(
+                    diff.field.to_string(),
+                    Signature {
+                        pattern: pattern.to_string(),
+                        offset: 0,
+                        fixup: None,
+                    },
+                );
+            }
+        }
+    }
+
+    // Only an entry that carried every function over is known to fit this executable
+    if flagged == 0 {
+        let resolved = entry.resolve(Some(&new_image))?;
+        entry.confirm_prologues(&new_image, &resolved)?;
+        entry.build = Some(new_image.fingerprint());
+    } else if database.find(client, new_version).is_some() {
+        // A partial entry only starts off a version that has none
+        println!("{flagged} function(s) need manual review");
+        println!("Kept the existing {client} {new_version} entry");
+        return Ok(1);
+    }
+    database.upsert(entry);
+    database.save(&path)?;
+    println!("Wrote {client} {new_version} to {}", path.display());
+    if flagged > 0 {
+        println!("{flagged} function(s) need manual review");
+        Ok(1)
+    } else {
+        Ok(0)
+    }
+}
+
+fn update_offsets(args: &Args) -> Result<i32, String> {
+    let source = match args.option("source") {
+        Some(source) => source.to_string(),
+        None => offset_feed::configured_source()?,
+    };
+    let installed = match args.option("exe") {
+        Some(exe) => {
+            let mut switcher = ClientSwitch {
+                game_path: exe.to_string(),
+                ..Default::default()
+            };
+            switcher.sync_with_installation();
+            Some(Installation {
+                client: switcher.client_type.offset_key().to_string(),
+                game_version: switcher.game_version()?,
+                image: PeFile::open(Path::new(exe))?,
+            })
+        }
+        None => None,
+    };
+
+    let path = args.database_path();
+    let report = offset_feed::update(&source, &path, installed.as_ref())?;
+    if report.up_to_date {
+        println!("Manifest {} was already applied", report.sequence);
+        return Ok(0);
+    }
+    for (entry, outcome) in &report.results {
+        match outcome {
+            MergeOutcome::Added => println!("added     {entry}"),
+            MergeOutcome::Updated => println!("updated   {entry}"),
+            MergeOutcome::Unchanged => println!("unchanged {entry}"),
+            MergeOutcome::Rejected(reason) => println!("rejected  {entry}: {reason}"),
+        }
+    }
+    println!(
+        "{} entr(ies) merged into {} from manifest {}",
+        report.changed(),
+        path.display(),
+        report.sequence
+    );
+    Ok(if report.rejected() > 0 { 1 } else { 0 })
+}
+
+fn island_command(args: &Args) -> Result<i32, String> {
+    let key = args.positional(0, "island command")?;
+    let command =
+        Command::from_key(key).ok_or_else(|| format!("Unknown island command '{key}'"))?;
+    let argument = match (command, args.option("feature")) {
+        (Command::DisableHook, None) => return Err("Missing --feature".to_string()),
+        (_, None) => 0,
+        (_, Some(key)) => {
+            Feature::from_key(key).ok_or_else(|| format!("Unknown feature '{key}'"))? as u32
+        }
+    };
+
+    let pid = match args.option("pid") {
+        Some(pid) => pid
+            .parse()
+            .map_err(|_| format!("Invalid process ID '{pid}'"))?,
+        None => find_game(args.option("exe"))?,
+    };
+    let island = open_environment(pid)?;
+    let env = island.environment();
+    let result = send_command(env, command, argument);
+    if command == Command::DumpStatus {
+        for feature in Feature::ALL {
+            println!(
+                "{}: {}",
+                feature.name(),
+                feature_status(&env.hook_report, feature)
+            );
+        }
+        match env.effective.read() {
+            Some((generation, values)) if generation > 0 => println!(
+                "In game: FOV {:.1} (game asked {:.1}{}), fog {}, {} FPS",
+                values.field_of_view,
+                values.original_field_of_view,
+                if values.low_fov_scene {
+                    ", low FOV scene"
+                } else {
+                    ""
+                },
+                if values.enable_fog { "on" } else { "off" },
+                values.target_frame_rate
+            ),
+            _ => println!("In game: not reported yet"),
+        }
+    }
+    match result {
+        Ok(message) => {
+            println!("{message}");
+            Ok(0)
+        }
+        Err(message) => {
+            println!("{message}");
+            Ok(1)
+        }
+    }
+}
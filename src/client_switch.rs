@@ -7,6 +7,41 @@ use std::path::Path;
 pub enum ClientType {
     Official,
     Bilibili,
+    Global,
+}
+
+impl ClientType {
+    /// Key of this client in the offset database.
+    pub fn offset_key(self) -> &'static str {
+        match self {
+            // Both CN channels ship the same executable
+            ClientType::Official | ClientType::Bilibili => "chinese",
+            ClientType::Global => "global",
+        }
+    }
+
+    pub fn process_name(self) -> &'static str {
+        match self {
+            ClientType::Official | ClientType::Bilibili => "YuanShen.exe",
+            ClientType::Global => "GenshinImpact.exe",
+        }
+    }
+
+    pub fn data_folder(self) -> &'static str {
+        match self {
+            ClientType::Official | ClientType::Bilibili => "YuanShen_Data",
+            ClientType::Global => "GenshinImpact_Data",
+        }
+    }
+
+    /// `channel=` value in config.ini, or `None` if the client has no channel to switch.
+    fn channel(self) -> Option<&'static str> {
+        match self {
+            ClientType::Official => Some("1"),
+            ClientType::Bilibili => Some("14"),
+            ClientType::Global => None,
+        }
+    }
 }
 
 pub struct ClientSwitch {
@@ -16,7 +51,7 @@ pub struct ClientSwitch {
 
 impl Default for ClientSwitch {
     fn default() -> Self {
-        Self {
+        let mut switcher = Self {
             game_path: read_to_string(format!("{ASSETS_PATH}/game_path.txt")).unwrap_or_else(
                 |_| {
                     "D:\\Program Files\\Genshin Impact\\Genshin Impact Game\\YuanShen.exe"
@@ -24,14 +59,58 @@ impl Default for ClientSwitch {
                 },
             ),
             client_type: ClientType::Official,
-        }
+        };
+        switcher.sync_with_installation();
+        switcher
     }
 }
 
 impl ClientSwitch {
+    /// Whether the selected executable is the global client.
+    pub fn is_global_installation(&self) -> bool {
+        Path::new(self.game_path.trim())
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.eq_ignore_ascii_case(ClientType::Global.process_name()))
+    }
+
+    /// Keep the client type consistent with the selected executable.
+    pub fn sync_with_installation(&mut self) {
+        if self.is_global_installation() {
+            self.client_type = ClientType::Global;
+        } else if self.client_type == ClientType::Global {
+            self.client_type = ClientType::Official;
+        }
+    }
+
+    /// Read `game_version` from the config.ini next to the game executable.
+    pub fn game_version(&self) -> Result<String, String> {
+        let exe_path = self.game_path.trim();
+        let game_dir = Path::new(exe_path).parent().ok_or("Invalid game path")?;
+        let config_path = game_dir.join("config.ini");
+        let content = read_to_string(&config_path)
+            .map_err(|_| format!("Cannot open config.ini: {}", config_path.display()))?;
+        content
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("game_version="))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| "game_version not found in config.ini".to_string())
+    }
+
     pub fn switch(&mut self) -> Result<(), String> {
         let exe_path = self.game_path.trim();
         let game_dir = Path::new(exe_path).parent().ok_or("Invalid game path")?;
+        let data_dir = game_dir.join(self.client_type.data_folder());
+        if !data_dir.is_dir() {
+            return Err(format!(
+                "Game data folder not found: {}",
+                data_dir.display()
+            ));
+        }
+        let Some(channel) = self.client_type.channel() else {
+            return Ok(());
+        };
         let config_path = game_dir.join("config.ini");
 
         // config.ini
@@ -46,13 +125,11 @@ impl ClientSwitch {
             file.read_to_string(&mut config_content)
                 .map_err(|_| "Failed to read config.ini")?;
         } // auto close file
+        let channel_line = format!("channel={channel}");
         let mut lines: Vec<&str> = config_content.lines().collect();
         for line in lines.iter_mut() {
             if line.starts_with("channel=") {
-                *line = match self.client_type {
-                    ClientType::Official => "channel=1",
-                    ClientType::Bilibili => "channel=14",
-                };
+                *line = &channel_line;
             }
         }
         let new_content = lines.join("\n");
@@ -1,54 +1,175 @@
 use crate::client_switch::{ClientSwitch, ClientType};
 use crate::hutao_config::{
-    ASSETS_PATH, CHINESE_OFFSETS, IslandEnvironment, IslandState, SHARED_MEMORY_NAME,
+    ASSETS_PATH, Command, Feature, FunctionOffsets, HookStatus, ISLAND_MAGIC, IslandEnvironment,
+    IslandError, IslandHeader, IslandState, LAUNCHER_LOG_FILE, LOG_RECORDS, LogLevel, ModuleTable,
+    PROTOCOL_VERSION, Settings, SharedEnvironment,
 };
-use crate::process_utils::{get_main_thread_id, is_process_running, kill_process_by_name};
+use crate::island_client::{
+    PendingCommand, feature_details, feature_status, find_game, open_environment,
+};
+use crate::launch_history::{LaunchHistory, LaunchRecord};
+use crate::offset_checker::check_offsets;
+use crate::offset_database::{OffsetDatabase, ResolvedOffsets};
+use crate::offset_feed::{self, Installation};
+use crate::pe_reader::PeFile;
+use crate::process_utils::{get_main_thread_id, is_game_running, kill_game};
 use eframe::egui;
-use std::ffi::{CString, c_void};
+use std::ffi::CString;
 use std::fs;
+use std::io::Write;
 use std::mem;
 use std::path::Path;
 use std::ptr;
+use std::sync::atomic::Ordering;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use windows_sys::Win32::Foundation::*;
-use windows_sys::Win32::Security::*;
 use windows_sys::Win32::System::Environment::*;
 use windows_sys::Win32::System::LibraryLoader::*;
-use windows_sys::Win32::System::Memory::*;
+use windows_sys::Win32::System::SystemInformation::GetLocalTime;
 use windows_sys::Win32::System::Threading::*;
 use windows_sys::Win32::UI::WindowsAndMessaging::*;
 
+/// How long `wait_for_dll` waits for the DLL to answer, in 100 ms steps.
+const DLL_RESPONSE_POLLS: u32 = 50;
+/// The DLL beats once a second; this long without a beat means it is not running.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Lines of DLL log kept for the log view; the log file keeps everything.
+const DLL_LOG_LINES: usize = 500;
+
 pub struct Launcher {
     pub switcher: ClientSwitch,
     pub status: String,
-    pub target_fps: i32,
-    pub field_of_view: f32,
-    pub disable_fog: bool,
-    pub fix_low_fov: bool,
-    pub remove_team_anim: bool,
-    pub redirect_craft: bool,
+    /// Values set in the launcher, including those of features switched off.
+    pub requested: Settings,
+    /// Features switched off in the launcher.
+    pub switched_off: Vec<Feature>,
+    /// `assets/offsets.json` has offsets for the global client.
+    global_supported: bool,
+    /// Leave the DLL running with the current settings when the launcher exits.
+    pub detach_on_exit: bool,
     // Inner state
-    shared_mem_handle: Option<HANDLE>,
-    shared_mem_ptr: Option<*mut IslandEnvironment>,
+    offsets: Option<(ModuleTable, FunctionOffsets)>,
+    disabled_features: Vec<Feature>,
+    /// Set while hooked launch is blocked because the game changed since the last launch.
+    pending_update: Option<BuildUpdate>,
+    /// Last heartbeat value seen from the DLL and when it changed.
+    last_heartbeat: Option<(u64, Instant)>,
+    /// Index of the next DLL log record to read.
+    log_cursor: u64,
+    dll_log: Vec<(LogLevel, String)>,
+    /// Command sent from the hook status panel, until the DLL acknowledges it.
+    pending_command: Option<PendingCommand>,
+    shared_mem: Option<SharedEnvironment>,
     game_pid: u32,
     game_process: Option<HANDLE>,
     game_thread: Option<HANDLE>,
 }
 
+/// Offsets picked for the installed build.
+struct SelectedOffsets {
+    resolved: ResolvedOffsets,
+    environment: (ModuleTable, FunctionOffsets),
+    /// The offset entry was recorded for exactly this executable.
+    confirmed: bool,
+    /// The offset entry was recorded for a different executable.
+    mismatch: bool,
+}
+
+/// A hooked launch held back because the offsets may not fit the installed build.
+struct BuildUpdate {
+    /// The build of the last hooked launch from this executable, if any.
+    previous: Option<LaunchRecord>,
+    installed: LaunchRecord,
+    /// There is an offset entry that **Confirm Offsets** can check; without
+    /// one the game can only be launched without hooks.
+    confirmable: bool,
+}
+
+/// What the launcher starts with: every feature on, the field of view ones at the game's values.
+fn default_settings() -> Settings {
+    Settings {
+        remove_open_team_progress: true,
+        redirect_craft_entry: true,
+        ..Settings::default()
+    }
+}
+
+enum SettingControl<'a> {
+    Value(&'static str, egui::DragValue<'a>),
+    Toggle(&'static str, &'a mut bool),
+}
+
+// The control of each settings field, by name. Destructured so that a new
+// field does not compile until it has one.
+fn setting_controls(settings: &mut Settings) -> [(&'static str, SettingControl<'_>); 6] {
+    let Settings {
+        field_of_view,
+        fix_low_fov_scene,
+        disable_fog,
+        target_frame_rate,
+        remove_open_team_progress,
+        redirect_craft_entry,
+    } = settings;
+    [
+        (
+            "target_frame_rate",
+            SettingControl::Value(
+                "Target FPS:",
+                egui::DragValue::new(target_frame_rate).range(30..=239),
+            ),
+        ),
+        (
+            "field_of_view",
+            SettingControl::Value(
+                "Field of View:",
+                egui::DragValue::new(field_of_view).range(1.0..=120.0),
+            ),
+        ),
+        (
+            "fix_low_fov_scene",
+            SettingControl::Toggle("Fix Low FOV Scenes", fix_low_fov_scene),
+        ),
+        (
+            "disable_fog",
+            SettingControl::Toggle("Disable Fog", disable_fog),
+        ),
+        (
+            "remove_open_team_progress",
+            SettingControl::Toggle("Remove Team Animation", remove_open_team_progress),
+        ),
+        (
+            "redirect_craft_entry",
+            SettingControl::Toggle("Redirect Crafting Table", redirect_craft_entry),
+        ),
+    ]
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+fn describe(result: Result<String, String>) -> String {
+    result.unwrap_or_else(|e| format!("Command failed: {e}"))
+}
+
 impl Default for Launcher {
     fn default() -> Self {
         Self {
             switcher: ClientSwitch::default(),
             status: String::new(),
-            target_fps: 60,
-            field_of_view: 45.0,
-            disable_fog: false,
-            fix_low_fov: false,
-            remove_team_anim: true,
-            redirect_craft: true,
-            shared_mem_handle: None,
-            shared_mem_ptr: None,
+            requested: default_settings(),
+            switched_off: Vec::new(),
+            global_supported: false,
+            detach_on_exit: false,
+            offsets: None,
+            disabled_features: Vec::new(),
+            pending_update: None,
+            last_heartbeat: None,
+            log_cursor: 0,
+            dll_log: Vec::new(),
+            pending_command: None,
+            shared_mem: None,
             game_pid: 0,
             game_process: None,
             game_thread: None,
@@ -68,6 +189,49 @@ impl eframe::App for Launcher {
 }
 
 impl Launcher {
+    /// A launcher that picks up the game a detached launcher left running.
+    pub fn new() -> Self {
+        let mut launcher = Self::default();
+        launcher.check_global_support();
+        if let Ok(pid) = launcher.reattach() {
+            launcher.status = format!("Reattached to the running game (process {pid}).");
+        }
+        launcher
+    }
+
+    /// Values of a feature beyond switching it on and off.
+    /// The controls of the settings `feature` declares, values first.
+    fn feature_settings_ui(&mut self, ui: &mut egui::Ui, feature: Feature) {
+        let names = feature.spec().settings;
+        let (values, toggles): (Vec<_>, Vec<_>) = setting_controls(&mut self.requested)
+            .into_iter()
+            .filter(|(name, _)| names.contains(name))
+            .map(|(_, control)| control)
+            .partition(|control| matches!(control, SettingControl::Value(..)));
+        // A feature that reads a single toggle is turned on and off by its switch
+        if values.is_empty() && toggles.len() == 1 {
+            return;
+        }
+        for row in [values, toggles] {
+            if row.is_empty() {
+                continue;
+            }
+            ui.horizontal(|ui| {
+                for control in row {
+                    match control {
+                        SettingControl::Value(label, value) => {
+                            ui.label(label);
+                            ui.add(value);
+                        }
+                        SettingControl::Toggle(label, value) => {
+                            ui.checkbox(value, label);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
     pub fn ui(&mut self, ui: &mut egui::Ui) {
         ui.heading("GI-Toolkit Launcher");
 
@@ -80,49 +244,58 @@ impl Launcher {
                     format!("{ASSETS_PATH}/game_path.txt"),
                     &self.switcher.game_path,
                 );
+                self.switcher.sync_with_installation();
             }
         });
 
         ui.horizontal(|ui| {
-            ui.radio_value(
-                &mut self.switcher.client_type,
-                ClientType::Official,
-                "Official",
-            );
-            ui.radio_value(
-                &mut self.switcher.client_type,
-                ClientType::Bilibili,
-                "Bilibili",
+            let global = self.switcher.is_global_installation();
+            ui.add_enabled_ui(!global, |ui| {
+                ui.radio_value(
+                    &mut self.switcher.client_type,
+                    ClientType::Official,
+                    "Official",
+                );
+                ui.radio_value(
+                    &mut self.switcher.client_type,
+                    ClientType::Bilibili,
+                    "Bilibili",
+                );
+            });
+            let label = if self.global_supported {
+                "Global"
+            } else {
+                "Global (unsupported)"
+            };
+            let radio = ui.add_enabled(
+                global,
+                egui::RadioButton::new(self.switcher.client_type == ClientType::Global, label),
             );
+            if !self.global_supported {
+                radio.on_hover_text(
+                    "No offsets for the global client yet; it can only be launched without hooks.\n\
+                    Offsets from the feed or import-island enable it.",
+                );
+            }
         });
 
         ui.separator();
 
-        ui.horizontal(|ui| {
-            ui.label("Target FPS:");
-            ui.add(egui::DragValue::new(&mut self.target_fps).range(30..=239));
-            ui.checkbox(&mut self.remove_team_anim, "Remove Team Animation");
-        });
-
-        ui.horizontal(|ui| {
-            ui.label("Field of View:");
-            ui.add(egui::DragValue::new(&mut self.field_of_view).range(1.0..=120.0));
-            ui.checkbox(&mut self.fix_low_fov, "Fix Low FOV Scenes");
-        });
-
-        ui.horizontal(|ui| {
-            ui.checkbox(&mut self.disable_fog, "Disable Fog");
-            ui.checkbox(&mut self.redirect_craft, "Redirect Crafting Table");
-        });
+        for feature in Feature::ALL {
+            let mut on = !self.switched_off.contains(&feature);
+            if ui.checkbox(&mut on, feature.name()).changed() {
+                if on {
+                    self.switched_off.retain(|&f| f != feature);
+                } else {
+                    self.switched_off.push(feature);
+                }
+            }
+            ui.add_enabled_ui(on, |ui| self.feature_settings_ui(ui, feature));
+        }
 
         ui.horizontal(|ui| {
             if ui.button("Launch Game").clicked() {
-                let exe_path = self.switcher.game_path.trim().to_string();
-                let proc_name = Path::new(&exe_path)
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("YuanShen.exe");
-                let process_found = is_process_running(proc_name);
+                let process_found = is_game_running(self.switcher.game_path.trim());
 
                 if process_found {
                     self.status = "confirm_kill_popup".to_string();
@@ -130,6 +303,9 @@ impl Launcher {
                     self.launch_game();
                 }
             }
+            if ui.button("Update Offsets").clicked() {
+                self.update_offsets();
+            }
             if ui.button("Apply").clicked() {
                 self.apply_settings();
             }
@@ -150,18 +326,19 @@ impl Launcher {
                 .collapsible(false)
                 .resizable(false)
                 .show(ui.ctx(), |ui| {
-                    ui.label("The game process is running.\nThis will terminate game process, do you want to continue?");
+                    ui.label("The game process is running.\nThis will terminate game process, do you want to continue?\nAttach keeps it running and takes over its settings.");
                     ui.horizontal(|ui| {
                         if ui.button("Yes").clicked() {
-                            let exe_path = self.switcher.game_path.trim().to_string();
-                            let proc_name = Path::new(&exe_path)
-                                .file_name()
-                                .and_then(|n| n.to_str())
-                                .unwrap_or("YuanShen.exe");
-                            let _ = kill_process_by_name(proc_name);
+                            let _ = kill_game(self.switcher.game_path.trim());
                             self.status.clear();
                             self.launch_game();
                         }
+                        if ui.button("Attach").clicked() {
+                            self.status = match self.reattach() {
+                                Ok(pid) => format!("Reattached to the running game (process {pid})."),
+                                Err(e) => format!("Attach failed: {e}"),
+                            };
+                        }
                         if ui.button("No").clicked() {
                             self.status.clear();
                         }
@@ -192,14 +369,226 @@ impl Launcher {
         if !self.status.is_empty() && self.status != "about_popup" {
             ui.label(&self.status);
         }
+
+        if self.shared_mem.is_some() {
+            ui.separator();
+            self.drain_dll_log();
+            self.hook_status_ui(ui);
+            ui.ctx().request_repaint_after(Duration::from_secs(1));
+        }
+
+        if !self.dll_log.is_empty() {
+            egui::CollapsingHeader::new("DLL Log").show(ui, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(160.0)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        for (level, line) in &self.dll_log {
+                            let text = egui::RichText::new(line).monospace();
+                            ui.label(match level {
+                                LogLevel::Error => text.color(ui.visuals().error_fg_color),
+                                LogLevel::Warn => text.color(ui.visuals().warn_fg_color),
+                                LogLevel::Info | LogLevel::Debug => text,
+                            });
+                        }
+                    });
+            });
+        }
+
+        if let Some(update) = &self.pending_update {
+            let confirmable = update.confirmable;
+            ui.horizontal(|ui| {
+                if confirmable && ui.button("Confirm Offsets").clicked() {
+                    self.confirm_offsets();
+                }
+                if ui.button("Launch Without Hooks").clicked() {
+                    self.launch_vanilla();
+                }
+            });
+        }
+    }
+
+    /// Live state of the injected DLL, as it reports it in the shared environment.
+    fn hook_status_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(ptr) = self.shared_mem_ptr() else {
+            return;
+        };
+        let header = unsafe { ptr::read_volatile(&raw const (*ptr).header) };
+        if header.dll_version == 0 {
+            ui.label("DLL: not loaded, no hooks installed");
+            return;
+        }
+        let report = unsafe { &(*ptr).hook_report };
+
+        if let Some(pending) = &self.pending_command {
+            match pending.poll(unsafe { &*ptr }) {
+                Some(result) => {
+                    self.status = describe(result);
+                    self.pending_command = None;
+                }
+                None => ui.ctx().request_repaint_after(Duration::from_millis(100)),
+            }
+        }
+
+        let heartbeat = report.heartbeat.load(Ordering::Acquire);
+        let now = Instant::now();
+        let changed_at = match self.last_heartbeat {
+            Some((last, at)) if last == heartbeat => at,
+            _ => {
+                self.last_heartbeat = Some((heartbeat, now));
+                now
+            }
+        };
+        let state = match header.state() {
+            Some(IslandState::Started) if now - changed_at < HEARTBEAT_TIMEOUT => "running",
+            Some(IslandState::Started) => "not responding",
+            Some(IslandState::Error) => "error",
+            Some(IslandState::Stopped) => "stopped",
+            Some(IslandState::None) => "starting",
+            None => "unknown state",
+        };
+        ui.label(format!("DLL: {state} (heartbeat {heartbeat})"));
+
+        // What the DLL was asked for next to what the game last ran with
+        let env = unsafe { &*ptr };
+        if let (Some((_, requested)), Some((generation, effective))) =
+            (env.settings.read(), env.effective.read())
+            && generation > 0
+        {
+            egui::Grid::new("effective_values").show(ui, |ui| {
+                ui.label("");
+                ui.label("Requested");
+                ui.label("In game");
+                ui.end_row();
+                ui.label("Field of View");
+                ui.label(format!("{:.1}", requested.field_of_view));
+                ui.label(format!(
+                    "{:.1} (game asked {:.1})",
+                    effective.field_of_view, effective.original_field_of_view
+                ));
+                ui.end_row();
+                ui.label("Fog");
+                ui.label(on_off(!requested.disable_fog));
+                ui.label(on_off(effective.enable_fog));
+                ui.end_row();
+                ui.label("Target FPS");
+                ui.label(requested.target_frame_rate.to_string());
+                ui.label(effective.target_frame_rate.to_string());
+                ui.end_row();
+                ui.label("Scene");
+                ui.label("");
+                ui.label(if effective.low_fov_scene {
+                    "low FOV"
+                } else {
+                    "normal"
+                });
+                ui.end_row();
+            });
+        }
+
+        // One command at a time; the reply shows up in the status line
+        let idle = self.pending_command.is_none();
+        for feature in Feature::ALL {
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "{}: {}",
+                    feature.name(),
+                    feature_status(report, feature)
+                ))
+                .on_hover_text(feature_details(feature));
+                if report.status(feature) == HookStatus::Installed
+                    && ui
+                        .add_enabled(idle, egui::Button::new("Disable").small())
+                        .clicked()
+                {
+                    self.send_command(Command::DisableHook, feature as u32);
+                }
+            });
+        }
+
+        ui.horizontal(|ui| {
+            for (label, command) in [
+                ("Re-resolve Offsets", Command::ReResolveOffsets),
+                ("Dump Status", Command::DumpStatus),
+                ("Unload DLL", Command::Unload),
+            ] {
+                if ui.add_enabled(idle, egui::Button::new(label)).clicked() {
+                    self.send_command(command, 0);
+                }
+            }
+        });
+        ui.checkbox(&mut self.detach_on_exit, "Keep Hooks After Exit");
+    }
+
+    /// Queue a command for the DLL; `hook_status_ui` reports the reply.
+    fn send_command(&mut self, command: Command, argument: u32) {
+        let Some(ptr) = self.shared_mem_ptr() else {
+            return;
+        };
+        match PendingCommand::submit(unsafe { &*ptr }, command, argument) {
+            Ok(pending) => {
+                self.status = format!("{}: waiting for the DLL", command.key());
+                self.pending_command = Some(pending);
+            }
+            Err(e) => self.status = describe(Err(e)),
+        }
+    }
+
+    /// Move new DLL log records into the log view and append them to the log file.
+    fn drain_dll_log(&mut self) {
+        let Some(ptr) = self.shared_mem_ptr() else {
+            return;
+        };
+        let drained = unsafe { (*ptr).log.drain(&mut self.log_cursor) };
+        if drained.entries.is_empty() && drained.lost == 0 {
+            return;
+        }
+
+        let mut time: SYSTEMTIME = unsafe { mem::zeroed() };
+        unsafe { GetLocalTime(&mut time) };
+        let timestamp = format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            time.wYear, time.wMonth, time.wDay, time.wHour, time.wMinute, time.wSecond
+        );
+        let mut lines = Vec::new();
+        if drained.lost > 0 {
+            lines.push((
+                LogLevel::Warn,
+                format!(
+                    "{} record(s) lost, the log was not read in time",
+                    drained.lost
+                ),
+            ));
+        }
+        for entry in drained.entries {
+            lines.push((
+                entry.level,
+                format!("[{}] {}", entry.level.name(), entry.message),
+            ));
+        }
+
+        if let Ok(mut file) = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(format!("{ASSETS_PATH}/{LAUNCHER_LOG_FILE}"))
+        {
+            for (_, line) in &lines {
+                let _ = writeln!(file, "{timestamp} {line}");
+            }
+        }
+        self.dll_log.extend(lines);
+        let excess = self.dll_log.len().saturating_sub(DLL_LOG_LINES);
+        self.dll_log.drain(..excess);
     }
 
     fn launch_game(&mut self) {
         // Clean up
         self.cleanup();
+        self.pending_update = None;
 
         self.switcher.game_path = std::fs::read_to_string(format!("{ASSETS_PATH}/game_path.txt"))
             .unwrap_or_else(|_| self.switcher.game_path.clone());
+        self.switcher.sync_with_installation();
 
         let exe_path = self.switcher.game_path.trim().to_string();
 
@@ -217,6 +606,63 @@ impl Launcher {
             return;
         }
 
+        let history_path = LaunchHistory::default_path();
+        let mut history = match LaunchHistory::load_or_default(&history_path) {
+            Ok(history) => history,
+            Err(e) => {
+                self.status = format!("Launch aborted: {e}");
+                return;
+            }
+        };
+        let (image, installed) = match self.installed_build() {
+            Ok(installed) => installed,
+            Err(e) => {
+                self.status = format!("Launch aborted: {e}");
+                return;
+            }
+        };
+        let previous = history.last(&exe_path).cloned();
+
+        // Pick offsets for the installed build before touching the game
+        let selected = match self.select_offsets(&image, &installed) {
+            Ok(selected) => selected,
+            Err(e) => {
+                self.hold_launch(&e, previous, installed, false);
+                return;
+            }
+        };
+        if selected.mismatch {
+            self.hold_launch(
+                "The offsets for this version were made for a different executable.",
+                previous,
+                installed,
+                true,
+            );
+            return;
+        }
+        // Offsets that worked for the previous build say nothing about this one
+        if let Some(last) = &previous
+            && last.build != installed.build
+            && !selected.confirmed
+        {
+            self.hold_launch(
+                "Game updated since the last launch.",
+                previous,
+                installed,
+                true,
+            );
+            return;
+        }
+
+        let disabled_note = selected
+            .resolved
+            .disabled
+            .iter()
+            .map(|(feature, reason)| format!("\n{} disabled: {reason}", feature.name()))
+            .collect::<String>();
+        self.offsets = Some(selected.environment);
+        self.disabled_features = selected.resolved.disabled.iter().map(|(f, _)| *f).collect();
+
         // Switch client if needed
         let switch_result = self.switcher.switch();
         if let Err(e) = switch_result {
@@ -224,7 +670,73 @@ impl Launcher {
             return;
         }
 
-        let game_dir = Path::new(&exe_path).parent().unwrap();
+        let pi = match self.spawn_game(&exe_path) {
+            Ok(pi) => pi,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        unsafe {
+            // Create shared memory
+            match self.create_shared_memory() {
+                Ok(_) => {}
+                Err(e) => {
+                    self.status = format!("Failed to create shared memory: {e}");
+                    return;
+                }
+            }
+
+            // Configure environment
+            self.initialize_environment();
+
+            thread::sleep(Duration::from_secs(10));
+
+            self.game_pid = pi.dwProcessId;
+            self.game_process = Some(pi.hProcess);
+            let hutao_result = self
+                .inject_hutao_dll(hutao_dll_dst.to_str().unwrap())
+                .and_then(|_| self.wait_for_dll());
+            match hutao_result {
+                Ok(_) => {
+                    self.status =
+                        format!("Game launched, DLL injected successfully!{disabled_note}");
+                    history.record(&exe_path, installed);
+                    if let Err(e) = history.save(&history_path) {
+                        self.status += &format!("\n{e}");
+                    }
+                }
+                Err(e) => {
+                    self.status = format!("Hutao DLL injection failed: {e}");
+                    CloseHandle(pi.hProcess);
+                    self.game_process = None;
+                }
+            }
+        }
+    }
+
+    /// Start the game without shared memory or injection.
+    fn launch_vanilla(&mut self) {
+        if is_game_running(self.switcher.game_path.trim()) {
+            self.status = "The game is already running.".to_string();
+            return;
+        }
+        self.cleanup();
+        self.pending_update = None;
+
+        if let Err(e) = self.switcher.switch() {
+            self.status = format!("Switch failed: {e}");
+            return;
+        }
+        let exe_path = self.switcher.game_path.trim().to_string();
+        self.status = match self.spawn_game(&exe_path) {
+            Ok(_) => "Game launched without hooks.".to_string(),
+            Err(e) => e,
+        };
+    }
+
+    fn spawn_game(&mut self, exe_path: &str) -> Result<PROCESS_INFORMATION, String> {
+        let game_dir = Path::new(exe_path).parent().unwrap();
         let game_dir_str = game_dir.to_str().unwrap();
         unsafe {
             let env_name = "__COMPAT_LAYER\0".encode_utf16().collect::<Vec<u16>>();
@@ -250,102 +762,322 @@ impl Launcher {
                 0,
                 ptr::null_mut(),
                 dir_c.as_ptr() as *const u8,
-                &mut si,
+                &si,
                 &mut pi,
             );
             if ok == 0 {
-                self.status = format!("CreateProcessA failed: {}", GetLastError());
-                return;
+                return Err(format!("CreateProcessA failed: {}", GetLastError()));
             }
             self.game_pid = pi.dwProcessId;
             self.game_process = Some(pi.hProcess);
             self.game_thread = Some(pi.hThread);
 
             CloseHandle(pi.hThread);
+            Ok(pi)
+        }
+    }
 
-            // Create shared memory
-            match self.create_shared_memory() {
-                Ok(_) => {}
-                Err(e) => {
-                    self.status = format!("Failed to create shared memory: {e}");
-                    return;
-                }
+    /// Block hooked launch until the user confirms the offsets for the installed
+    /// build or launches without hooks.
+    fn hold_launch(
+        &mut self,
+        reason: &str,
+        previous: Option<LaunchRecord>,
+        installed: LaunchRecord,
+        confirmable: bool,
+    ) {
+        let last = previous
+            .as_ref()
+            .map_or("none".to_string(), |p| p.to_string());
+        let next = if confirmable {
+            "Hooked launch is blocked until the offsets are confirmed for this build."
+        } else {
+            "There are no usable offsets for this build; it can only be launched without hooks."
+        };
+        self.status = format!("{reason}\nlast launch: {last}\ninstalled: {installed}\n{next}");
+        self.pending_update = Some(BuildUpdate {
+            previous,
+            installed,
+            confirmable,
+        });
+    }
+
+    /// Check the selected offsets against the updated executable and, if every
+    /// one of them passes, record them as made for it.
+    fn confirm_offsets(&mut self) {
+        let Some(update) = &self.pending_update else {
+            return;
+        };
+        let result = (|| {
+            let path = OffsetDatabase::default_path();
+            let mut database = OffsetDatabase::load(&path)?;
+            let image = PeFile::open(Path::new(self.switcher.game_path.trim()))?;
+            let mut entry = database
+                .select(
+                    self.switcher.client_type.offset_key(),
+                    &update.installed.game_version,
+                    &update.installed.build,
+                )?
+                .entry
+                .clone();
+            let resolved = entry.resolve(Some(&image))?;
+            // Fields of disabled features are left out on purpose; the rest must pass
+            let enabled: Vec<&str> = Feature::ALL
+                .into_iter()
+                .filter(|f| !resolved.disabled.iter().any(|(d, _)| d == f))
+                .flat_map(Feature::offset_fields)
+                .collect();
+            if enabled.is_empty() {
+                return Err("No feature has usable offsets for this build".to_string());
+            }
+            let failed: Vec<String> = check_offsets(&image, &resolved.offsets)
+                .iter()
+                .filter(|r| enabled.contains(&r.field) && !r.passed())
+                .map(|r| format!("{}: {}", r.field, r.problems.join("; ")))
+                .collect();
+            if !failed.is_empty() {
+                return Err(format!(
+                    "Offsets do not match this build:\n{}",
+                    failed.join("\n")
+                ));
+            }
+            entry
+                .confirm_prologues(&image, &resolved)
+                .map_err(|e| format!("Offsets do not match this build:\n{e}"))?;
+            entry.build = Some(update.installed.build.clone());
+            database.upsert(entry);
+            database.save(&path)
+        })();
+        match result {
+            Ok(()) => {
+                let from = update.previous.as_ref().map_or(String::new(), |p| {
+                    format!(" (updated from {})", p.game_version)
+                });
+                self.status = format!(
+                    "Offsets confirmed for {}{from}. Launch the game again.",
+                    update.installed
+                );
+                self.pending_update = None;
             }
+            Err(e) => self.status = format!("Cannot confirm offsets: {e}"),
+        }
+    }
 
-            // Configure environment
-            self.configure_environment();
+    fn create_shared_memory(&mut self) -> Result<(), String> {
+        self.pending_command = None;
+        self.shared_mem = Some(SharedEnvironment::create(self.game_pid)?);
+        Ok(())
+    }
 
-            thread::sleep(Duration::from_secs(10));
+    /// Map the environment of a game that a detached launcher left running,
+    /// and take over the settings it holds.
+    fn reattach(&mut self) -> Result<u32, String> {
+        if self.shared_mem.is_some() {
+            return Err("The launcher is already attached to a game".to_string());
+        }
+        let pid = find_game(Some(self.switcher.game_path.trim()))?;
+        let island = open_environment(pid)?;
+        let env = island.environment();
+        let state = unsafe { ptr::read_volatile(&raw const env.header.state) };
+        if IslandState::from_code(state) != Some(IslandState::Started) {
+            return Err(format!("The DLL in process {pid} is not running"));
+        }
 
-            self.game_pid = pi.dwProcessId;
-            self.game_process = Some(pi.hProcess);
-            let hutao_result = self.inject_hutao_dll(hutao_dll_dst.to_str().unwrap());
-            match hutao_result {
-                Ok(_) => {
-                    self.status = "Game launched, DLL injected successfully!".to_string();
-                }
-                Err(e) => {
-                    self.status = format!("Hutao DLL injection failed: {e}");
-                    CloseHandle(pi.hProcess);
-                    self.game_process = None;
+        // Features with nothing to do in the DLL count as switched off; their
+        // values here are kept for switching them back on
+        if let Some((_, settings)) = env.settings.read() {
+            self.switched_off.clear();
+            for feature in Feature::ALL {
+                let spec = feature.spec();
+                if spec.active(&settings) {
+                    spec.copy_settings(&mut self.requested, &settings);
+                } else {
+                    self.switched_off.push(feature);
                 }
             }
         }
+        // Features the DLL skipped have no usable offsets; keep them off
+        self.disabled_features = Feature::ALL
+            .into_iter()
+            .filter(|&f| env.hook_report.status(f) == HookStatus::Skipped)
+            .collect();
+        // The last ring's worth of log, rather than a report of everything overwritten
+        let head = env.log.next_index.load(Ordering::Acquire);
+        self.log_cursor = head.saturating_sub(LOG_RECORDS as u64);
+        self.last_heartbeat = None;
+
+        self.pending_command = None;
+        self.shared_mem = Some(island);
+        self.game_pid = pid;
+        // Exiting should not undo what the previous launcher left in force
+        self.detach_on_exit = true;
+        Ok(pid)
     }
 
-    fn create_shared_memory(&mut self) -> Result<(), String> {
-        unsafe {
-            let mut sa = SECURITY_ATTRIBUTES {
-                nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
-                lpSecurityDescriptor: ptr::null_mut(),
-                bInheritHandle: TRUE,
-            };
-            let name = CString::new(SHARED_MEMORY_NAME).unwrap();
-            let h_map = CreateFileMappingA(
-                INVALID_HANDLE_VALUE,
-                &mut sa,
-                PAGE_READWRITE,
-                0,
-                mem::size_of::<IslandEnvironment>() as u32,
-                name.as_ptr() as *const u8,
-            );
-            if h_map.is_null() {
-                return Err("CreateFileMappingA failed".to_string());
-            }
-            let p_mem = MapViewOfFile(
-                h_map,
-                FILE_MAP_ALL_ACCESS,
-                0,
-                0,
-                mem::size_of::<IslandEnvironment>(),
-            );
-            if p_mem.Value.is_null() {
-                CloseHandle(h_map);
-                return Err("MapViewOfFile failed".to_string());
+    fn shared_mem_ptr(&self) -> Option<*mut IslandEnvironment> {
+        self.shared_mem.as_ref().map(SharedEnvironment::as_ptr)
+    }
+
+    /// The installed game executable and the build it is.
+    fn installed_build(&self) -> Result<(PeFile, LaunchRecord), String> {
+        let game_version = self.switcher.game_version()?;
+        let image = PeFile::open(Path::new(self.switcher.game_path.trim()))?;
+        let installed = LaunchRecord {
+            game_version,
+            build: image.fingerprint(),
+        };
+        Ok((image, installed))
+    }
+
+    fn select_offsets(
+        &self,
+        image: &PeFile,
+        installed: &LaunchRecord,
+    ) -> Result<SelectedOffsets, String> {
+        let database = OffsetDatabase::load(&OffsetDatabase::default_path())?;
+        let selection = database.select(
+            self.switcher.client_type.offset_key(),
+            &installed.game_version,
+            &installed.build,
+        )?;
+        let resolved = selection.entry.resolve(Some(image))?;
+        let environment = resolved.to_environment()?;
+        Ok(SelectedOffsets {
+            confirmed: selection.entry.build.as_ref() == Some(&installed.build),
+            mismatch: selection.mismatch,
+            resolved,
+            environment,
+        })
+    }
+
+    /// Look up whether the offset database has any global entry.
+    fn check_global_support(&mut self) {
+        self.global_supported = OffsetDatabase::load(&OffsetDatabase::default_path())
+            .is_ok_and(|database| database.has_client(ClientType::Global.offset_key()));
+    }
+
+    /// Merge the offset feed, checking entries for the installed build against it.
+    fn update_offsets(&mut self) {
+        let result = offset_feed::configured_source().and_then(|source| {
+            let installed = PeFile::open(Path::new(self.switcher.game_path.trim()))
+                .and_then(|image| {
+                    Ok(Installation {
+                        client: self.switcher.client_type.offset_key().to_string(),
+                        game_version: self.switcher.game_version()?,
+                        image,
+                    })
+                })
+                .ok();
+            offset_feed::update(&source, &OffsetDatabase::default_path(), installed.as_ref())
+        });
+        self.check_global_support();
+        self.status = match result {
+            Ok(report) if report.up_to_date => format!(
+                "Offset feed: manifest {} was already applied.",
+                report.sequence
+            ),
+            Ok(report) => {
+                let rejected = report
+                    .results
+                    .iter()
+                    .filter_map(|(entry, outcome)| match outcome {
+                        offset_feed::MergeOutcome::Rejected(reason) => {
+                            Some(format!("\nRejected {entry}: {reason}"))
+                        }
+                        _ => None,
+                    })
+                    .collect::<String>();
+                format!(
+                    "Offset feed: {} entr(ies) updated from manifest {}.{rejected}",
+                    report.changed(),
+                    report.sequence
+                )
             }
-            self.shared_mem_handle = Some(h_map);
-            self.shared_mem_ptr = Some(p_mem.Value as *mut IslandEnvironment);
-        }
-        Ok(())
+            Err(e) => format!("Offset feed update failed: {e}"),
+        };
     }
 
-    fn configure_environment(&mut self) {
-        if let Some(ptr) = self.shared_mem_ptr {
+    fn feature_enabled(&self, feature: Feature) -> bool {
+        !self.switched_off.contains(&feature) && !self.disabled_features.contains(&feature)
+    }
+
+    /// Write the header, modules and offsets into a freshly created mapping.
+    fn initialize_environment(&mut self) {
+        if let (Some(ptr), Some((modules, offsets))) = (self.shared_mem_ptr(), self.offsets) {
             unsafe {
                 // Zero out the memory
                 std::ptr::write_bytes(ptr, 0, 1);
 
                 let env = &mut *ptr;
-                env.function_offsets = CHINESE_OFFSETS;
-                env.field_of_view = self.field_of_view;
-                env.fix_low_fov_scene = if self.fix_low_fov { 1 } else { 0 };
-                env.disable_fog = if self.disable_fog { 1 } else { 0 };
-                env.target_frame_rate = self.target_fps;
-                env.remove_open_team_progress = if self.remove_team_anim { 1 } else { 0 };
-                env.redirect_craft_entry = if self.redirect_craft { 1 } else { 0 };
-                env.state = IslandState::Started;
+                env.header = IslandHeader::new();
+                env.modules = modules;
+                env.function_offsets = offsets;
+            }
+        }
+        self.log_cursor = 0;
+        self.configure_environment();
+    }
+
+    /// What the DLL is given: the requested values, with those of features
+    /// that are off put back to the game's own.
+    fn settings(&self) -> Settings {
+        let mut settings = self.requested;
+        for feature in Feature::ALL {
+            if !self.feature_enabled(feature) {
+                feature
+                    .spec()
+                    .copy_settings(&mut settings, &Settings::default());
+            }
+        }
+        settings
+    }
+
+    fn configure_environment(&mut self) {
+        if let Some(ptr) = self.shared_mem_ptr() {
+            unsafe { (*ptr).settings.write(&self.settings()) };
+        }
+    }
+
+    /// Wait for the DLL to answer the header and report what it said.
+    fn wait_for_dll(&self) -> Result<(), String> {
+        let Some(ptr) = self.shared_mem_ptr() else {
+            return Err("Shared memory is not mapped".to_string());
+        };
+        for _ in 0..DLL_RESPONSE_POLLS {
+            let header = unsafe { ptr::read_volatile(&raw const (*ptr).header) };
+            // A DLL from before the header writes its state over the magic
+            if header.magic != ISLAND_MAGIC {
+                return Err(
+                    "Incompatible hutao_minhook.dll: it predates the versioned protocol"
+                        .to_string(),
+                );
+            }
+            if header.dll_version != 0 {
+                if header.state() != Some(IslandState::Error) {
+                    return Ok(());
+                }
+                let error = IslandError::from_code(header.error);
+                let name = error.map_or(format!("unknown error {}", header.error), |e| {
+                    e.name().to_string()
+                });
+                return Err(match error {
+                    Some(
+                        IslandError::BadMagic
+                        | IslandError::VersionMismatch
+                        | IslandError::SizeMismatch,
+                    ) => format!(
+                        "Incompatible hutao_minhook.dll: {name} (DLL protocol {}, launcher protocol {PROTOCOL_VERSION})",
+                        header.dll_version
+                    ),
+                    _ => format!(
+                        "hutao_minhook.dll failed: {name} (error {})",
+                        header.last_error
+                    ),
+                });
             }
+            thread::sleep(Duration::from_millis(100));
         }
+        Err("hutao_minhook.dll did not respond".to_string())
     }
 
     fn inject_hutao_dll(&self, dll_path: &str) -> Result<(), String> {
@@ -403,35 +1135,29 @@ impl Launcher {
     }
 
     pub fn reset_settings(&mut self) {
-        self.target_fps = 60;
-        self.field_of_view = 45.0;
-        self.disable_fog = false;
-        self.fix_low_fov = false;
-        self.remove_team_anim = true;
-        self.redirect_craft = true;
+        self.requested = default_settings();
+        self.switched_off.clear();
         self.configure_environment();
         self.status = "Settings reset to default.".to_string();
     }
 
     pub fn cleanup(&mut self) {
         unsafe {
-            if let Some(ptr) = self.shared_mem_ptr {
-                let env = &mut *ptr;
-                env.target_frame_rate = 60;
-                env.field_of_view = 45.0;
-                env.fix_low_fov_scene = 0;
-                env.disable_fog = 0;
-                env.remove_open_team_progress = 0;
-                env.redirect_craft_entry = 0;
-                env.state = IslandState::Stopped;
-                UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS {
-                    Value: ptr as *mut c_void,
-                });
-                self.shared_mem_ptr = None;
+            if let Some(ptr) = self.shared_mem_ptr() {
+                self.drain_dll_log();
+                if let Some(pending) = self.pending_command.take() {
+                    pending.abandon(&*ptr);
+                }
+                if !self.detach_on_exit {
+                    (*ptr).settings.write(&Settings::default());
+                    ptr::write_volatile(&raw mut (*ptr).header.state, IslandState::Stopped as u32);
+                }
             }
-            if let Some(h_map) = self.shared_mem_handle {
-                CloseHandle(h_map);
-                self.shared_mem_handle = None;
+            // A detached environment stays with the game for the next launcher
+            if let Some(island) = self.shared_mem.take()
+                && self.detach_on_exit
+            {
+                island.detach();
             }
             if let Some(h_proc) = self.game_process {
                 CloseHandle(h_proc);
@@ -440,3 +1166,21 @@ impl Launcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_settings_field_has_one_control() {
+        let mut settings = Settings::default();
+        let mut names: Vec<&str> = setting_controls(&mut settings)
+            .iter()
+            .map(|(name, _)| *name)
+            .collect();
+        let mut expected = Settings::FIELD_NAMES.to_vec();
+        names.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(names, expected);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::hutao_config::{ASSETS_PATH, FunctionOffsets};
+use crate::offset_database::{FunctionAddress, OffsetSet};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Method addresses by `Namespace.Class$$Method`, as Il2CppDumper names them.
+/// Overloaded names keep every address so they can be reported as ambiguous.
+pub type MethodTable = HashMap<String, Vec<u64>>;
+
+#[derive(Deserialize)]
+struct ScriptJson {
+    #[serde(rename = "ScriptMethod", default)]
+    script_method: Vec<ScriptMethod>,
+}
+
+#[derive(Deserialize)]
+struct ScriptMethod {
+    #[serde(rename = "Address")]
+    address: u64,
+    #[serde(rename = "Name")]
+    name: String,
+}
+
+/// Read either a script.json or a dump.cs listing, picked by content.
+pub fn load_methods(path: &Path) -> Result<MethodTable, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+    if content.trim_start().starts_with('{') {
+        parse_script_json(&content)
+    } else {
+        Ok(parse_dump_cs(&content))
+    }
+}
+
+pub fn parse_script_json(content: &str) -> Result<MethodTable, String> {
+    let script: ScriptJson =
+        serde_json::from_str(content).map_err(|e| format!("Invalid script.json: {e}"))?;
+    let mut methods = MethodTable::new();
+    for method in script.script_method {
+        methods.entry(method.name).or_default().push(method.address);
+    }
+    Ok(methods)
+}
+
+/// Parse the `// RVA: 0x... ` comments of a dump.cs, naming each method like script.json does.
+pub fn parse_dump_cs(content: &str) -> MethodTable {
+    let mut methods = MethodTable::new();
+    let mut namespace = String::new();
+    let mut class = String::new();
+    let mut pending_rva = None;
+
+    for line in content.lines().map(str::trim) {
+        if let Some(ns) = line.strip_prefix("// Namespace:") {
+            namespace = ns.trim().to_string();
+        } else if let Some(rest) = line.strip_prefix("// RVA:") {
+            pending_rva = rest
+                .split_whitespace()
+                .next()
+                .and_then(|v| u64::from_str_radix(v.trim_start_matches("0x"), 16).ok());
+        } else if let Some(rva) = pending_rva.take() {
+            if let Some(name) = method_name(line) {
+                let qualified = if namespace.is_empty() {
+                    format!("{class}$${name}")
+                } else {
+                    format!("{namespace}.{class}$${name}")
+                };
+                methods.entry(qualified).or_default().push(rva);
+            }
+        } else if let Some(name) = type_name(line) {
+            class = name;
+        }
+    }
+    methods
+}
+
+fn type_name(line: &str) -> Option<String> {
+    let mut tokens = line.split_whitespace();
+    tokens.find(|t| matches!(*t, "class" | "struct" | "interface" | "enum"))?;
+    let name = tokens.next()?;
+    let end = name.find(['<', ':']).unwrap_or(name.len());
+    Some(name[..end].to_string())
+}
+
+fn method_name(line: &str) -> Option<&str> {
+    let before_args = &line[..line.find('(')?];
+    let name = before_args.split_whitespace().last()?;
+    Some(name.split('<').next().unwrap_or(name))
+}
+
+/// Which Il2CppDumper method names to try for each `FunctionOffsets` field.
+pub struct NameTable {
+    pub names: BTreeMap<String, Vec<String>>,
+}
+
+impl NameTable {
+    pub fn default_path() -> PathBuf {
+        Path::new(ASSETS_PATH).join("il2cpp_names.json")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read name table {}: {e}", path.display()))?;
+        let names = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid name table {}: {e}", path.display()))?;
+        Ok(Self { names })
+    }
+}
+
+pub struct ImportResult {
+    pub offsets: OffsetSet,
+    /// Fields that could not be resolved, with the reason.
+    pub unresolved: Vec<(&'static str, String)>,
+}
+
+pub fn map_offsets(methods: &MethodTable, table: &NameTable) -> ImportResult {
+    let mut offsets = OffsetSet::default();
+    let mut unresolved = Vec::new();
+
+    for field in FunctionOffsets::FIELD_NAMES {
+        let candidates = table.names.get(field).map(Vec::as_slice).unwrap_or(&[]);
+        if candidates.is_empty() {
+            unresolved.push((field, "no method name in the name table".to_string()));
+            continue;
+        }
+        let Some((name, addresses)) = candidates
+            .iter()
+            .find_map(|name| methods.get(name).map(|a| (name, a)))
+        else {
+            unresolved.push((field, format!("not found: {}", candidates.join(", "))));
+            continue;
+        };
+        match addresses.as_slice() {
+            [address] => offsets.set(field, FunctionAddress::in_game(*address)),
+            _ => unresolved.push((
+                field,
+                format!("{name} is overloaded ({} addresses)", addresses.len()),
+            )),
+        }
+    }
+    ImportResult {
+        offsets,
+        unresolved,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(entries: &[(&str, &[&str])]) -> NameTable {
+        NameTable {
+            names: entries
+                .iter()
+                .map(|(field, names)| {
+                    let names = names.iter().map(|n| n.to_string()).collect();
+                    (field.to_string(), names)
+                })
+                .collect(),
+        }
+    }
+
+    fn reason<'a>(result: &'a ImportResult, field: &str) -> Option<&'a str> {
+        result
+            .unresolved
+            .iter()
+            .find(|(f, _)| *f == field)
+            .map(|(_, reason)| reason.as_str())
+    }
+
+    #[test]
+    fn shipped_name_table_lists_every_field() {
+        let names: BTreeMap<String, Vec<String>> =
+            serde_json::from_str(include_str!("../assets/il2cpp_names.json")).unwrap();
+        for field in FunctionOffsets::FIELD_NAMES {
+            assert!(names.contains_key(field), "{field}");
+        }
+        for (field, candidates) in &names {
+            assert!(
+                FunctionOffsets::FIELD_NAMES.contains(&field.as_str()),
+                "{field}"
+            );
+            assert!(candidates.iter().all(|n| n.contains("$$")), "{field}");
+        }
+    }
+
+    #[test]
+    fn script_json_keeps_every_address_of_an_overloaded_name() {
+        let methods = parse_script_json(
+            r#"{"ScriptMethod": [
+                {"Address": 4096, "Name": "UnityEngine.Camera$$set_fieldOfView", "Signature": ""},
+                {"Address": 8192, "Name": "System.String$$CreateString"},
+                {"Address": 12288, "Name": "System.String$$CreateString"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            methods.get("UnityEngine.Camera$$set_fieldOfView"),
+            Some(&vec![4096])
+        );
+        assert_eq!(
+            methods.get("System.String$$CreateString"),
+            Some(&vec![8192, 12288])
+        );
+        assert!(parse_script_json("{\"ScriptMethod\": 1}").is_err());
+    }
+
+    #[test]
+    fn mapping_resolves_unique_names_and_reports_the_rest() {
+        let methods = MethodTable::from([
+            (
+                "UnityEngine.Camera$$set_fieldOfView".to_string(),
+                vec![0x1000],
+            ),
+            (
+                "System.String$$CreateString".to_string(),
+                vec![0x2000, 0x3000],
+            ),
+        ]);
+        let result = map_offsets(
+            &methods,
+            &table(&[
+                (
+                    "set_field_of_view",
+                    &["Missing$$First", "UnityEngine.Camera$$set_fieldOfView"],
+                ),
+                ("find_string", &["System.String$$CreateString"]),
+                (
+                    "set_target_frame_rate",
+                    &["UnityEngine.Application$$set_targetFrameRate"],
+                ),
+                ("open_team", &[]),
+            ]),
+        );
+
+        assert_eq!(
+            result.offsets.get("set_field_of_view"),
+            Some(&FunctionAddress::in_game(0x1000))
+        );
+        assert_eq!(reason(&result, "set_field_of_view"), None);
+        assert_eq!(
+            reason(&result, "find_string"),
+            Some("System.String$$CreateString is overloaded (2 addresses)")
+        );
+        assert!(result.offsets.get("find_string").is_none());
+        assert_eq!(
+            reason(&result, "set_target_frame_rate"),
+            Some("not found: UnityEngine.Application$$set_targetFrameRate")
+        );
+        for field in ["open_team", "check_can_enter"] {
+            assert_eq!(
+                reason(&result, field),
+                Some("no method name in the name table")
+            );
+        }
+    }
+
+    #[test]
+    fn dump_cs_methods_are_named_like_script_json() {
+        let dump = "// Namespace: UnityEngine\n\
+            public sealed class RenderSettings : Object\n\
+            {\n\
+            \t// RVA: 0x10B4C100 Offset: 0x10B4B500 VA: 0x1910B4C100\n\
+            \tpublic static void set_fog(bool value) { }\n\
+            }\n";
+        let methods = parse_dump_cs(dump);
+        assert_eq!(
+            methods.get("UnityEngine.RenderSettings$$set_fog"),
+            Some(&vec![0x10B4C100])
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Driving the DLL of a running game through the shared environment, from the
+//! launcher window or from the command line.
+
+use crate::client_switch::ClientType;
+use crate::hutao_config::{
+    Command, CommandReply, CommandResult, Feature, HookReport, HookStatus, IslandEnvironment,
+    PROLOGUE_MISMATCH, SharedEnvironment,
+};
+use crate::process_utils::{for_each_process_at_path, for_each_process_by_name};
+use min_hook_rs::HookError;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the DLL to acknowledge a command. It polls ten times a second.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The game process to talk to: the one started from `exe_path`, or else the
+/// only game running.
+pub fn find_game(exe_path: Option<&str>) -> Result<u32, String> {
+    let mut pids = Vec::new();
+    match exe_path {
+        Some(exe_path) => {
+            for_each_process_at_path(exe_path, |pid| pids.push(pid));
+        }
+        None => {
+            for client in [ClientType::Official, ClientType::Global] {
+                for_each_process_by_name(client.process_name(), |pid| pids.push(pid));
+            }
+        }
+    }
+    match pids[..] {
+        [pid] => Ok(pid),
+        [] => Err("The game is not running".to_string()),
+        _ => Err(format!(
+            "{} game processes are running, pick one with --pid",
+            pids.len()
+        )),
+    }
+}
+
+/// Open the environment of game process `pid` and check that it speaks this
+/// protocol version.
+pub fn open_environment(pid: u32) -> Result<SharedEnvironment, String> {
+    let island = SharedEnvironment::open(pid)
+        .map_err(|e| format!("Process {pid} is not a hooked game: {e}"))?;
+    island
+        .environment()
+        .header
+        .validate()
+        .map_err(|e| format!("Incompatible environment: {}", e.name()))?;
+    Ok(island)
+}
+
+/// Send a command and describe the acknowledgement.
+pub fn send_command(
+    env: &IslandEnvironment,
+    command: Command,
+    argument: u32,
+) -> Result<String, String> {
+    let reply = env.commands.send(command, argument, COMMAND_TIMEOUT)?;
+    describe_reply(command, reply)
+}
+
+/// A command queued for the DLL, for callers that cannot block on the reply.
+pub struct PendingCommand {
+    command: Command,
+    slot: usize,
+    deadline: Instant,
+}
+
+impl PendingCommand {
+    pub fn submit(
+        env: &IslandEnvironment,
+        command: Command,
+        argument: u32,
+    ) -> Result<Self, String> {
+        let slot = env
+            .commands
+            .submit(command, argument)
+            .ok_or("Every command slot is busy")?;
+        Ok(Self {
+            command,
+            slot,
+            deadline: Instant::now() + COMMAND_TIMEOUT,
+        })
+    }
+
+    /// The described acknowledgement once the DLL has sent it, or an error once
+    /// it has taken too long. `None` while still waiting.
+    pub fn poll(&self, env: &IslandEnvironment) -> Option<Result<String, String>> {
+        if let Some(reply) = env.commands.take_reply(self.slot) {
+            return Some(describe_reply(self.command, reply));
+        }
+        if Instant::now() < self.deadline {
+            return None;
+        }
+        env.commands.abandon(self.slot);
+        Some(Err(format!(
+            "No reply to {} within {COMMAND_TIMEOUT:?}",
+            self.command.key()
+        )))
+    }
+
+    /// Stop waiting, freeing the slot whether or not the DLL has replied.
+    pub fn abandon(self, env: &IslandEnvironment) {
+        env.commands.abandon(self.slot);
+    }
+}
+
+fn describe_reply(command: Command, reply: CommandReply) -> Result<String, String> {
+    let key = command.key();
+    match CommandResult::from_code(reply.result) {
+        Some(CommandResult::Ok) => Ok(match command {
+            Command::ReResolveOffsets | Command::DumpStatus => {
+                format!("{key}: ok, hooked: {}", feature_list(reply.value))
+            }
+            Command::DisableHook | Command::Unload => format!("{key}: ok"),
+        }),
+        Some(CommandResult::HookError) => Err(format!(
+            "{key}: MinHook error: {}",
+            HookError::from_code(reply.value as i32).as_str()
+        )),
+        Some(result) => Err(format!("{key}: {}", result.name())),
+        None => Err(format!("{key}: unknown result {}", reply.result)),
+    }
+}
+
+fn feature_list(mask: u32) -> String {
+    let keys: Vec<&str> = Feature::ALL
+        .into_iter()
+        .filter(|f| mask & f.bit() != 0)
+        .map(Feature::key)
+        .collect();
+    if keys.is_empty() {
+        "none".to_string()
+    } else {
+        keys.join(", ")
+    }
+}
+
+/// What the DLL reports for one feature, for display.
+pub fn feature_status(report: &HookReport, feature: Feature) -> String {
+    match report.status(feature) {
+        HookStatus::Pending => "pending".to_string(),
+        HookStatus::Installed => "hooked".to_string(),
+        HookStatus::Skipped => "skipped".to_string(),
+        HookStatus::Disabled => "disabled".to_string(),
+        HookStatus::Inactive => "off".to_string(),
+        HookStatus::Failed if report.minhook_status(feature) == PROLOGUE_MISMATCH => {
+            "failed: unexpected prologue".to_string()
+        }
+        HookStatus::Failed => format!(
+            "failed: {}",
+            HookError::from_code(report.minhook_status(feature)).as_str()
+        ),
+    }
+}
+
+/// What a feature hooks, calls and reads, as declared in the protocol.
+pub fn feature_details(feature: Feature) -> String {
+    let spec = feature.spec();
+    format!(
+        "Hooks {}\nCalls {}\nSettings: {}",
+        spec.hooked,
+        spec.helpers.join(", "),
+        spec.settings.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(result: i32, value: u32) -> CommandReply {
+        CommandReply { result, value }
+    }
+
+    #[test]
+    fn replies_are_described_by_result() {
+        let hooked = Feature::ALL[0].bit() | Feature::ALL[1].bit();
+        assert_eq!(
+            describe_reply(Command::DumpStatus, reply(CommandResult::Ok as i32, hooked)),
+            Ok(format!(
+                "dump-status: ok, hooked: {}, {}",
+                Feature::ALL[0].key(),
+                Feature::ALL[1].key()
+            ))
+        );
+        assert_eq!(
+            describe_reply(
+                Command::ReResolveOffsets,
+                reply(CommandResult::Ok as i32, 0)
+            ),
+            Ok("re-resolve: ok, hooked: none".to_string())
+        );
+        assert_eq!(
+            describe_reply(Command::Unload, reply(CommandResult::Ok as i32, 0)),
+            Ok("unload: ok".to_string())
+        );
+        assert_eq!(
+            describe_reply(
+                Command::DisableHook,
+                reply(
+                    CommandResult::HookError as i32,
+                    HookError::NotCreated as u32
+                )
+            ),
+            Err("disable-hook: MinHook error: Not created".to_string())
+        );
+        assert_eq!(
+            describe_reply(
+                Command::DisableHook,
+                reply(CommandResult::NotInstalled as i32, 0)
+            ),
+            Err("disable-hook: hook not installed".to_string())
+        );
+        assert_eq!(
+            describe_reply(Command::DumpStatus, reply(42, 0)),
+            Err("dump-status: unknown result 42".to_string())
+        );
+    }
+
+    #[test]
+    fn feature_status_names_each_state_and_failure() {
+        let report = HookReport {
+            installed: Default::default(),
+            skipped: Default::default(),
+            failed: Default::default(),
+            disabled: Default::default(),
+            inactive: Default::default(),
+            minhook_status: Default::default(),
+            heartbeat: Default::default(),
+        };
+        let feature = Feature::ALL[0];
+        assert_eq!(feature_status(&report, feature), "pending");
+        for (status, shown) in [
+            (HookStatus::Installed, "hooked"),
+            (HookStatus::Skipped, "skipped"),
+            (HookStatus::Disabled, "disabled"),
+            (HookStatus::Inactive, "off"),
+        ] {
+            report.mark(feature, status, 0);
+            assert_eq!(feature_status(&report, feature), shown);
+        }
+        report.mark(feature, HookStatus::Failed, PROLOGUE_MISMATCH);
+        assert_eq!(
+            feature_status(&report, feature),
+            "failed: unexpected prologue"
+        );
+        report.mark(feature, HookStatus::Failed, HookError::NotExecutable as i32);
+        assert_eq!(feature_status(&report, feature), "failed: Not executable");
+        report.mark(feature, HookStatus::Failed, 99);
+        assert_eq!(feature_status(&report, feature), "failed: Unknown error");
+    }
+}
@@ -0,0 +1,173 @@
+//! Offsets in the layout published for UnlockerIsland / Snap.Hutao:
+//! `{ "Chinese": { "FindString": 4993584, ... }, "Oversea": { ... } }`.
+
+use crate::hutao_config::FunctionOffsets;
+use crate::offset_database::{FunctionAddress, OffsetSet};
+use serde_json::{Map, Value};
+
+/// Region keys of the UnlockerIsland layout and our offset database clients.
+const REGIONS: [(&str, &str); 2] = [("Chinese", "chinese"), ("Oversea", "global")];
+
+pub fn client_for_region(region: &str) -> Option<&'static str> {
+    REGIONS
+        .iter()
+        .find(|(r, _)| *r == region)
+        .map(|(_, client)| *client)
+}
+
+pub fn region_for_client(client: &str) -> Option<&'static str> {
+    REGIONS
+        .iter()
+        .find(|(_, c)| *c == client)
+        .map(|(region, _)| *region)
+}
+
+/// `set_field_of_view` -> `SetFieldOfView`
+fn upstream_name(field: &str) -> String {
+    field
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+pub struct ImportedRegion {
+    pub region: String,
+    pub offsets: OffsetSet,
+    pub missing: Vec<&'static str>,
+}
+
+pub fn parse(content: &str) -> Result<Vec<ImportedRegion>, String> {
+    let root: Map<String, Value> = serde_json::from_str(content)
+        .map_err(|e| format!("Invalid UnlockerIsland offsets: {e}"))?;
+    let mut regions = Vec::new();
+    for (region, value) in root {
+        let Some(fields) = value.as_object() else {
+            continue;
+        };
+        let mut offsets = OffsetSet::default();
+        let mut missing = Vec::new();
+        for field in FunctionOffsets::FIELD_NAMES {
+            match fields.get(&upstream_name(field)).and_then(Value::as_u64) {
+                Some(rva) => offsets.set(field, FunctionAddress::in_game(rva)),
+                None => missing.push(field),
+            }
+        }
+        regions.push(ImportedRegion {
+            region,
+            offsets,
+            missing,
+        });
+    }
+    Ok(regions)
+}
+
+/// Render `(region, offsets)` pairs in the UnlockerIsland layout. That layout
+/// only knows game executable RVAs, so module-qualified offsets are rejected.
+pub fn render(regions: &[(&str, &OffsetSet)]) -> Result<String, String> {
+    let mut root = Map::new();
+    for (region, offsets) in regions {
+        let mut fields = Map::new();
+        for field in FunctionOffsets::FIELD_NAMES {
+            let rva = match offsets.get(field) {
+                None => 0,
+                Some(address) => address.game_rva().ok_or_else(|| {
+                    format!("{region}.{field} is in {address}, which the layout cannot express")
+                })?,
+            };
+            fields.insert(upstream_name(field), Value::from(rva));
+        }
+        root.insert(region.to_string(), Value::Object(fields));
+    }
+    Ok(serde_json::to_string_pretty(&Value::Object(root)).unwrap() + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_names_convert_to_pascal_case() {
+        assert_eq!(upstream_name("set_field_of_view"), "SetFieldOfView");
+        assert_eq!(upstream_name("find_string"), "FindString");
+    }
+
+    #[test]
+    fn regions_map_both_ways_and_unknown_ones_do_not() {
+        for (region, client) in REGIONS {
+            assert_eq!(client_for_region(region), Some(client));
+            assert_eq!(region_for_client(client), Some(region));
+        }
+        assert_eq!(client_for_region("Bilibili"), None);
+        assert_eq!(region_for_client("bilibili"), None);
+    }
+
+    #[test]
+    fn parsing_reads_every_region_and_lists_missing_fields() {
+        let regions = parse(
+            r#"{
+                "Chinese": { "FindString": 4993584, "SetFieldOfView": "16" },
+                "Bilibili": { "FindString": 1 },
+                "Version": "5.8"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(regions.len(), 2);
+
+        let chinese = regions.iter().find(|r| r.region == "Chinese").unwrap();
+        assert_eq!(
+            chinese.offsets.get("find_string"),
+            Some(&FunctionAddress::in_game(4993584))
+        );
+        assert!(chinese.missing.contains(&"set_field_of_view"));
+        assert!(!chinese.missing.contains(&"find_string"));
+        assert_eq!(
+            chinese.missing.len(),
+            FunctionOffsets::FIELD_NAMES.len() - 1
+        );
+        assert!(regions.iter().any(|r| r.region == "Bilibili"));
+
+        assert!(parse("[]").is_err());
+    }
+
+    #[test]
+    fn rendering_round_trips_and_writes_zero_for_missing_fields() {
+        let mut offsets = OffsetSet::default();
+        offsets.set("find_string", FunctionAddress::in_game(4993584));
+        let rendered = render(&[("Oversea", &offsets)]).unwrap();
+
+        let root: Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(root["Oversea"]["FindString"], 4993584);
+        assert_eq!(root["Oversea"]["SetFieldOfView"], 0);
+
+        let parsed = parse(&rendered).unwrap();
+        assert_eq!(
+            parsed[0].offsets.get("find_string"),
+            Some(&FunctionAddress::in_game(4993584))
+        );
+        // Zero is how the layout writes a missing offset
+        assert!(parsed[0].offsets.get("set_field_of_view").is_none());
+    }
+
+    #[test]
+    fn offsets_in_other_modules_cannot_be_rendered() {
+        let mut offsets = OffsetSet::default();
+        offsets.set(
+            "set_field_of_view",
+            FunctionAddress {
+                module: Some("UnityPlayer.dll".to_string()),
+                rva: 0x1000,
+            },
+        );
+        let error = render(&[("Chinese", &offsets)]).unwrap_err();
+        assert!(
+            error.starts_with("Chinese.set_field_of_view is in "),
+            "{error}"
+        );
+    }
+}
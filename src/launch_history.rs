@@ -0,0 +1,58 @@
+//! Builds the game was last launched with hooks, so an update is noticed
+//! before offsets made for the old build are written into the new one.
+
+use crate::hutao_config::{ASSETS_PATH, LAUNCH_HISTORY_FILE};
+use crate::pe_reader::BuildFingerprint;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LaunchRecord {
+    pub game_version: String,
+    pub build: BuildFingerprint,
+}
+
+impl fmt::Display for LaunchRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.game_version, self.build)
+    }
+}
+
+/// Last successful hooked launch per game executable path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LaunchHistory(BTreeMap<String, LaunchRecord>);
+
+impl LaunchHistory {
+    pub fn default_path() -> PathBuf {
+        Path::new(ASSETS_PATH).join(LAUNCH_HISTORY_FILE)
+    }
+
+    /// Load the history, or start an empty one if nothing was launched yet.
+    pub fn load_or_default(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read launch history {}: {e}", path.display()))?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid launch history {}: {e}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize launch history: {e}"))?;
+        std::fs::write(path, content + "\n")
+            .map_err(|e| format!("Cannot write launch history {}: {e}", path.display()))
+    }
+
+    pub fn last(&self, exe_path: &str) -> Option<&LaunchRecord> {
+        self.0.get(exe_path)
+    }
+
+    pub fn record(&mut self, exe_path: &str, record: LaunchRecord) {
+        self.0.insert(exe_path.to_string(), record);
+    }
+}
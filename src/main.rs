@@ -1,11 +1,29 @@
+mod cli;
 mod client_switch;
 mod hutao_config;
 mod hutao_launcher;
+mod il2cpp_import;
+mod island_client;
+mod island_format;
+mod launch_history;
+mod offset_checker;
+mod offset_database;
+mod offset_feed;
+mod pe_reader;
 mod process_utils;
+mod signature_generator;
+mod signature_scanner;
+// Widget sandbox for trying out egui controls, not part of the app
+#[allow(dead_code)]
 mod widget_test;
 use crate::hutao_launcher::Launcher as App;
 
 fn main() -> eframe::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([400.0, 300.0])
@@ -16,6 +34,6 @@ fn main() -> eframe::Result<()> {
     eframe::run_native(
         "GI-Toolkit",
         options,
-        Box::new(|_cc| Ok(Box::new(App::default()))),
+        Box::new(|_cc| Ok(Box::new(App::new()))),
     )
 }
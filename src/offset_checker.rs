@@ -0,0 +1,176 @@
+use crate::hutao_config::FunctionOffsets;
+use crate::offset_database::{FunctionAddress, OffsetSet};
+use crate::pe_reader::PeFile;
+
+const FUNCTION_ALIGNMENT: u32 = 16;
+const INT3: u8 = 0xCC;
+const NOP: u8 = 0x90;
+
+/// Result of checking one `FunctionOffsets` field against a game executable.
+pub struct FieldReport {
+    pub field: &'static str,
+    pub address: Option<FunctionAddress>,
+    /// Section the RVA falls into, if any.
+    pub section: Option<String>,
+    /// False for functions outside the game executable, which cannot be checked against it.
+    pub checked: bool,
+    pub problems: Vec<String>,
+}
+
+impl FieldReport {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check that every offset points at a plausible function start in `image`.
+pub fn check_offsets(image: &PeFile, offsets: &OffsetSet) -> Vec<FieldReport> {
+    FunctionOffsets::FIELD_NAMES
+        .iter()
+        .map(|&field| {
+            let address = offsets.get(field);
+            let mut report = FieldReport {
+                field,
+                address: address.cloned(),
+                section: None,
+                checked: true,
+                problems: Vec::new(),
+            };
+            let Some(address) = address else {
+                report.problems.push("no offset".to_string());
+                return report;
+            };
+
+            let collisions: Vec<&str> = FunctionOffsets::FIELD_NAMES
+                .iter()
+                .filter(|&&other| other != field && offsets.get(other) == Some(address))
+                .copied()
+                .collect();
+            if !collisions.is_empty() {
+                report
+                    .problems
+                    .push(format!("same address as {}", collisions.join(", ")));
+            }
+
+            let Some(rva) = address.game_rva() else {
+                report.checked = false;
+                return report;
+            };
+            let Some((rva, section)) = u32::try_from(rva)
+                .ok()
+                .and_then(|rva| image.section_for_rva(rva).map(|s| (rva, s)))
+            else {
+                report.problems.push("outside every section".to_string());
+                return report;
+            };
+            report.section = Some(section.name.clone());
+
+            if !section.is_executable() {
+                report
+                    .problems
+                    .push(format!("in non-executable section {}", section.name));
+                return report;
+            }
+            if rva % FUNCTION_ALIGNMENT != 0 {
+                report
+                    .problems
+                    .push(format!("not {FUNCTION_ALIGNMENT}-byte aligned"));
+            }
+            // The first function of a section has nothing before it
+            if rva != section.virtual_address {
+                match image.read_at_rva(rva - 1, 1) {
+                    Some([INT3 | NOP]) => {}
+                    Some([b]) => report
+                        .problems
+                        .push(format!("preceded by {b:#04x} instead of int3/nop padding")),
+                    _ => report.problems.push("not backed by file data".to_string()),
+                }
+            }
+            report
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe_reader::tests::{fixture, image_with_code};
+
+    fn offsets(fields: &[(&str, u64)]) -> OffsetSet {
+        let mut offsets = OffsetSet::default();
+        for &(field, rva) in fields {
+            offsets.set(field, FunctionAddress::in_game(rva));
+        }
+        offsets
+    }
+
+    fn problems<'a>(reports: &'a [FieldReport], field: &str) -> &'a [String] {
+        &reports.iter().find(|r| r.field == field).unwrap().problems
+    }
+
+    #[test]
+    fn function_starts_need_alignment_and_padding_before_them() {
+        let mut code = vec![INT3; 0x40];
+        code[..4].copy_from_slice(&[0x48, 0x83, 0xEC, 0x28]);
+        code[0x1F] = 0xC3;
+        code[0x2F] = NOP;
+        let image = PeFile::parse(image_with_code(&code)).unwrap();
+        let reports = check_offsets(
+            &image,
+            &offsets(&[
+                ("find_string", 0x1000),
+                ("set_field_of_view", 0x1010),
+                ("set_target_frame_rate", 0x1020),
+                ("set_enable_fog_rendering", 0x1030),
+                ("open_team", 0x1008),
+            ]),
+        );
+
+        for field in [
+            "find_string",
+            "set_field_of_view",
+            "set_enable_fog_rendering",
+        ] {
+            assert!(problems(&reports, field).is_empty(), "{field}");
+        }
+        assert_eq!(
+            problems(&reports, "set_target_frame_rate"),
+            ["preceded by 0xc3 instead of int3/nop padding"]
+        );
+        assert_eq!(problems(&reports, "open_team"), ["not 16-byte aligned"]);
+        let find_string = reports.iter().find(|r| r.field == "find_string").unwrap();
+        assert_eq!(find_string.section.as_deref(), Some(".text"));
+    }
+
+    #[test]
+    fn offsets_outside_code_or_missing_fail() {
+        let image = PeFile::parse(fixture()).unwrap();
+        let mut set = offsets(&[
+            ("find_string", 0x9000),
+            ("set_field_of_view", 0x2000),
+            ("set_target_frame_rate", 0x1000),
+            ("set_enable_fog_rendering", 0x1000),
+        ]);
+        set.set(
+            "open_team",
+            FunctionAddress {
+                module: Some("UnityPlayer.dll".to_string()),
+                rva: 0x1000,
+            },
+        );
+        let reports = check_offsets(&image, &set);
+
+        assert_eq!(problems(&reports, "find_string"), ["outside every section"]);
+        assert_eq!(
+            problems(&reports, "set_field_of_view"),
+            ["in non-executable section .data"]
+        );
+        assert_eq!(
+            problems(&reports, "set_target_frame_rate"),
+            ["same address as set_enable_fog_rendering"]
+        );
+        let open_team = reports.iter().find(|r| r.field == "open_team").unwrap();
+        assert!(open_team.passed() && !open_team.checked);
+        assert_eq!(problems(&reports, "check_can_enter"), ["no offset"]);
+    }
+}
@@ -0,0 +1,501 @@
+use crate::hutao_config::{
+    ASSETS_PATH, Feature, FunctionOffsets, MAX_MODULES, MODULE_NAME_LEN, ModuleTable,
+    OFFSET_DATABASE_FILE, Prologue,
+};
+use crate::pe_reader::{BuildFingerprint, PeFile};
+use crate::signature_generator::prologue_at;
+use crate::signature_scanner::{Pattern, Signature};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where a function lives. Written as a bare number for the game executable,
+/// or as `{ "module": "UnityPlayer.dll", "rva": 123 }` for any other module.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "AddressRepr", into = "AddressRepr")]
+pub struct FunctionAddress {
+    /// `None` for the game executable.
+    pub module: Option<String>,
+    pub rva: u64,
+}
+
+impl FunctionAddress {
+    pub fn in_game(rva: u64) -> Self {
+        Self { module: None, rva }
+    }
+
+    /// RVA inside the game executable, if that is where the function lives.
+    pub fn game_rva(&self) -> Option<u64> {
+        self.module.is_none().then_some(self.rva)
+    }
+}
+
+impl std::fmt::Display for FunctionAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.module {
+            Some(module) => write!(f, "{module}+{:#x}", self.rva),
+            None => write!(f, "{:#010x}", self.rva),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum AddressRepr {
+    Rva(u64),
+    Qualified { module: String, rva: u64 },
+}
+
+impl From<AddressRepr> for FunctionAddress {
+    fn from(repr: AddressRepr) -> Self {
+        match repr {
+            AddressRepr::Rva(rva) => Self::in_game(rva),
+            AddressRepr::Qualified { module, rva } => Self {
+                module: Some(module),
+                rva,
+            },
+        }
+    }
+}
+
+impl From<FunctionAddress> for AddressRepr {
+    fn from(address: FunctionAddress) -> Self {
+        match address.module {
+            None => AddressRepr::Rva(address.rva),
+            Some(module) => AddressRepr::Qualified {
+                module,
+                rva: address.rva,
+            },
+        }
+    }
+}
+
+/// Function addresses keyed by `FunctionOffsets` field name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OffsetSet(BTreeMap<String, FunctionAddress>);
+
+impl OffsetSet {
+    /// The address of `field`, or `None` if it is missing or zero.
+    pub fn get(&self, field: &str) -> Option<&FunctionAddress> {
+        self.0.get(field).filter(|address| address.rva != 0)
+    }
+
+    /// Whether no field has a usable address.
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(|address| address.rva == 0)
+    }
+
+    pub fn set(&mut self, field: &str, address: FunctionAddress) {
+        self.0.insert(field.to_string(), address);
+    }
+
+    pub fn remove(&mut self, field: &str) {
+        self.0.remove(field);
+    }
+
+    /// Lay out the offsets for the shared environment, numbering each module once.
+    pub fn to_environment(&self) -> Result<(ModuleTable, FunctionOffsets), String> {
+        let mut modules: ModuleTable = [[0; MODULE_NAME_LEN]; MAX_MODULES];
+        let mut module_count = 1;
+        let mut offsets = FunctionOffsets::default();
+
+        for (field, address) in &self.0 {
+            let slot = offsets
+                .get_mut(field)
+                .ok_or_else(|| format!("Unknown offset field '{field}'"))?;
+            slot.rva = address.rva;
+            let Some(module) = &address.module else {
+                continue;
+            };
+            if !module.is_ascii() || module.len() >= MODULE_NAME_LEN {
+                return Err(format!("Invalid module name '{module}' for {field}"));
+            }
+            let index = match modules[1..module_count].iter().position(|name| {
+                let len = name.iter().position(|&b| b == 0).unwrap_or(MODULE_NAME_LEN);
+                name[..len].eq_ignore_ascii_case(module.as_bytes())
+            }) {
+                Some(found) => found + 1,
+                None if module_count < MAX_MODULES => {
+                    modules[module_count][..module.len()].copy_from_slice(module.as_bytes());
+                    module_count += 1;
+                    module_count - 1
+                }
+                None => return Err(format!("More than {} modules", MAX_MODULES - 1)),
+            };
+            slot.module = index as u32;
+        }
+        Ok((modules, offsets))
+    }
+}
+
+/// One offset set, valid for a single client and game build.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OffsetEntry {
+    pub client: String,
+    pub game_version: String,
+    /// The exact executable these offsets were made for, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildFingerprint>,
+    #[serde(default)]
+    pub offsets: OffsetSet,
+    /// Signatures keyed by `FunctionOffsets` field name, resolved against the game executable.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub signatures: BTreeMap<String, Signature>,
+    /// Bytes each function starts with, keyed by `FunctionOffsets` field name, as a
+    /// pattern such as `48 89 5C 24 ?? 57`. The DLL refuses to hook a function that
+    /// does not start with them. A signature that matches at the function start
+    /// serves as its prologue unless one is given here.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub prologues: BTreeMap<String, String>,
+}
+
+/// Offsets ready to be injected, with the features that had to be turned off.
+#[derive(Debug)]
+pub struct ResolvedOffsets {
+    pub offsets: OffsetSet,
+    pub disabled: Vec<(Feature, String)>,
+    /// Expected prologues of the functions in `offsets` that have one.
+    pub prologues: BTreeMap<String, Pattern>,
+}
+
+impl ResolvedOffsets {
+    /// Lay out the offsets and their prologues for the shared environment.
+    pub fn to_environment(&self) -> Result<(ModuleTable, FunctionOffsets), String> {
+        let (modules, mut offsets) = self.offsets.to_environment()?;
+        for (field, pattern) in &self.prologues {
+            if let Some(prologue) = offsets.prologue_mut(field) {
+                *prologue = Prologue::from_pattern(pattern.bytes());
+            }
+        }
+        Ok((modules, offsets))
+    }
+}
+
+impl OffsetEntry {
+    /// For an entry whose offsets were just confirmed against `image`: check
+    /// the prologues it has and give each function in the game executable
+    /// without one the bytes it starts with there. Returns how many were added.
+    pub fn confirm_prologues(
+        &mut self,
+        image: &PeFile,
+        resolved: &ResolvedOffsets,
+    ) -> Result<usize, String> {
+        let mut mismatched = Vec::new();
+        let mut added = BTreeMap::new();
+        for field in FunctionOffsets::FIELD_NAMES {
+            let Some(rva) = resolved
+                .offsets
+                .get(field)
+                .and_then(FunctionAddress::game_rva)
+                .and_then(|rva| u32::try_from(rva).ok())
+            else {
+                continue;
+            };
+            match resolved.prologues.get(field) {
+                Some(prologue) => {
+                    let code = image.read_at_rva(rva, prologue.len()).unwrap_or(&[]);
+                    if !prologue.matches_at(code, 0) {
+                        mismatched.push(format!("{field} does not start with {prologue}"));
+                    }
+                }
+                None => {
+                    if let Some(prologue) = prologue_at(image, rva) {
+                        added.insert(field.to_string(), prologue.to_string());
+                    }
+                }
+            }
+        }
+        if !mismatched.is_empty() {
+            return Err(mismatched.join("; "));
+        }
+        let count = added.len();
+        self.prologues.extend(added);
+        Ok(count)
+    }
+
+    /// Resolve signatures against the game executable. A feature with any
+    /// unresolved function is disabled as a whole and its offsets are zeroed.
+    pub fn resolve(&self, image: Option<&PeFile>) -> Result<ResolvedOffsets, String> {
+        let mut offsets = self.offsets.clone();
+        let mut failures: BTreeMap<&str, String> = BTreeMap::new();
+
+        if !self.signatures.is_empty() {
+            let image = image.ok_or("Game executable is required to resolve signatures")?;
+            let regions = image.executable_regions();
+            for (field, signature) in &self.signatures {
+                if !FunctionOffsets::FIELD_NAMES.contains(&field.as_str()) {
+                    return Err(format!("Unknown offset field '{field}' in signatures"));
+                }
+                match signature.resolve(&regions) {
+                    Ok(rva) => offsets.set(field, FunctionAddress::in_game(rva as u64)),
+                    Err(e) => {
+                        offsets.remove(field);
+                        failures.insert(field, e.to_string());
+                    }
+                }
+            }
+        }
+        for field in FunctionOffsets::FIELD_NAMES {
+            if offsets.get(field).is_none() {
+                failures
+                    .entry(field)
+                    .or_insert_with(|| "no offset".to_string());
+            }
+        }
+
+        let mut disabled = Vec::new();
+        for feature in Feature::ALL {
+            let reasons: Vec<String> = feature
+                .offset_fields()
+                .filter_map(|field| failures.get(field).map(|e| format!("{field}: {e}")))
+                .collect();
+            if reasons.is_empty() {
+                continue;
+            }
+            for field in feature.offset_fields() {
+                offsets.remove(field);
+            }
+            disabled.push((feature, reasons.join("; ")));
+        }
+
+        let mut prologues = BTreeMap::new();
+        for (field, signature) in &self.signatures {
+            if signature.fixup.is_none()
+                && signature.offset == 0
+                && let Ok(pattern) = Pattern::parse(&signature.pattern)
+            {
+                prologues.insert(field.clone(), pattern);
+            }
+        }
+        for (field, text) in &self.prologues {
+            if !FunctionOffsets::FIELD_NAMES.contains(&field.as_str()) {
+                return Err(format!("Unknown offset field '{field}' in prologues"));
+            }
+            let pattern =
+                Pattern::parse(text).map_err(|e| format!("Invalid prologue for {field}: {e}"))?;
+            prologues.insert(field.clone(), pattern);
+        }
+        prologues.retain(|field, _| offsets.get(field).is_some());
+        Ok(ResolvedOffsets {
+            offsets,
+            disabled,
+            prologues,
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct OffsetDatabase {
+    pub entries: Vec<OffsetEntry>,
+    /// Sequence number of the last offset feed manifest merged in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feed_sequence: Option<u64>,
+}
+
+impl OffsetDatabase {
+    pub fn default_path() -> PathBuf {
+        Path::new(ASSETS_PATH).join(OFFSET_DATABASE_FILE)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read offset database {}: {e}", path.display()))?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid offset database {}: {e}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize offset database: {e}"))?;
+        std::fs::write(path, content + "\n")
+            .map_err(|e| format!("Cannot write offset database {}: {e}", path.display()))
+    }
+
+    /// Load the database, or start an empty one if the file does not exist yet.
+    pub fn load_or_default(path: &Path) -> Result<Self, String> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn find(&self, client: &str, game_version: &str) -> Option<&OffsetEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.client == client && e.game_version == game_version)
+    }
+
+    /// Whether there are offsets for any version of `client`.
+    pub fn has_client(&self, client: &str) -> bool {
+        self.entries.iter().any(|e| e.client == client)
+    }
+
+    /// Add an entry, replacing any existing one for the same client and build.
+    pub fn upsert(&mut self, entry: OffsetEntry) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| e.client == entry.client && e.game_version == entry.game_version)
+        {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// Pick the offsets for the installed build. Never falls back to another build.
+    ///
+    /// An entry recorded for the exact fingerprint wins; otherwise the entry for
+    /// `game_version` is used, flagged as a mismatch if it was recorded for a
+    /// different executable.
+    pub fn select(
+        &self,
+        client: &str,
+        game_version: &str,
+        fingerprint: &BuildFingerprint,
+    ) -> Result<Selection<'_>, String> {
+        let for_client = || self.entries.iter().filter(|e| e.client == client);
+        if let Some(entry) = for_client().find(|e| e.build.as_ref() == Some(fingerprint)) {
+            return Ok(Selection {
+                entry,
+                mismatch: false,
+            });
+        }
+        if let Some(entry) = self.find(client, game_version) {
+            return Ok(Selection {
+                entry,
+                mismatch: entry.build.is_some(),
+            });
+        }
+        let known: Vec<&str> = for_client().map(|e| e.game_version.as_str()).collect();
+        if known.is_empty() {
+            Err(format!(
+                "No offsets for client '{client}' in the offset database"
+            ))
+        } else {
+            Err(format!(
+                "No offsets for {client} build {game_version} (known builds: {})",
+                known.join(", ")
+            ))
+        }
+    }
+}
+
+/// The entry `OffsetDatabase::select` picked for the installed build.
+#[derive(Debug)]
+pub struct Selection<'a> {
+    pub entry: &'a OffsetEntry,
+    /// The entry was recorded for a different executable of the same version,
+    /// so it has to be confirmed before it is used.
+    pub mismatch: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hutao_config::PROLOGUE_LEN;
+
+    fn fingerprint(content_hash: &str) -> BuildFingerprint {
+        BuildFingerprint {
+            machine: 0x8664,
+            time_date_stamp: 0,
+            size_of_image: 0x1000,
+            content_hash: content_hash.to_string(),
+        }
+    }
+
+    fn entry(game_version: &str, build: Option<&str>) -> OffsetEntry {
+        OffsetEntry {
+            client: "chinese".to_string(),
+            game_version: game_version.to_string(),
+            build: build.map(fingerprint),
+            offsets: OffsetSet::default(),
+            signatures: BTreeMap::new(),
+            prologues: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn select_prefers_the_exact_build() {
+        let database = OffsetDatabase {
+            entries: vec![entry("5.8.0", Some("aa")), entry("5.7.0", Some("bb"))],
+            ..Default::default()
+        };
+        let selection = database
+            .select("chinese", "5.8.0", &fingerprint("bb"))
+            .unwrap();
+        assert_eq!(selection.entry.game_version, "5.7.0");
+        assert!(!selection.mismatch);
+    }
+
+    #[test]
+    fn select_flags_an_entry_for_another_executable() {
+        let database = OffsetDatabase {
+            entries: vec![entry("5.8.0", Some("aa"))],
+            ..Default::default()
+        };
+        let selection = database
+            .select("chinese", "5.8.0", &fingerprint("cc"))
+            .unwrap();
+        assert_eq!(selection.entry.game_version, "5.8.0");
+        assert!(selection.mismatch);
+
+        let database = OffsetDatabase {
+            entries: vec![entry("5.8.0", None)],
+            ..Default::default()
+        };
+        let selection = database
+            .select("chinese", "5.8.0", &fingerprint("cc"))
+            .unwrap();
+        assert!(!selection.mismatch);
+    }
+
+    #[test]
+    fn select_never_falls_back_to_another_version() {
+        let database = OffsetDatabase {
+            entries: vec![entry("5.7.0", Some("aa"))],
+            ..Default::default()
+        };
+        let error = database
+            .select("chinese", "5.8.0", &fingerprint("cc"))
+            .unwrap_err();
+        assert!(error.contains("known builds: 5.7.0"), "{error}");
+        assert!(
+            database
+                .select("global", "5.8.0", &fingerprint("cc"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn confirming_fills_in_missing_prologues_and_checks_the_rest() {
+        let image = PeFile::parse(crate::pe_reader::tests::fixture()).unwrap();
+        let mut offsets = OffsetSet::default();
+        offsets.set("find_string", FunctionAddress::in_game(0x1000));
+        let mut resolved = ResolvedOffsets {
+            offsets,
+            disabled: Vec::new(),
+            prologues: BTreeMap::new(),
+        };
+        let mut confirmed = entry("5.8.0", None);
+        assert_eq!(confirmed.confirm_prologues(&image, &resolved), Ok(1));
+        let prologue = Pattern::parse(&confirmed.prologues["find_string"]).unwrap();
+        assert_eq!(prologue.len(), PROLOGUE_LEN);
+        assert_eq!(
+            &prologue.bytes()[..5],
+            &[0x55, 0x48, 0x89, 0xE5, 0xC3].map(Some)
+        );
+
+        resolved
+            .prologues
+            .insert("find_string".to_string(), prologue);
+        assert_eq!(confirmed.confirm_prologues(&image, &resolved), Ok(0));
+
+        resolved.prologues.insert(
+            "find_string".to_string(),
+            Pattern::parse("48 83 EC").unwrap(),
+        );
+        assert!(confirmed.confirm_prologues(&image, &resolved).is_err());
+    }
+}
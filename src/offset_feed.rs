@@ -0,0 +1,634 @@
+//! Offset feed: a signed offset manifest fetched from a URL or a `file://`
+//! mirror and merged into the local offset database.
+//!
+//! The feed is a JSON envelope `{ "manifest": "...", "signature": "..." }`.
+//! `manifest` is the text of an offset database with a sequence number
+//! (`{ "sequence": 7, "entries": [...] }`) and `signature` is the hex encoded
+//! raw `r || s` ECDSA P-256 signature over the SHA-256 of that text. It is
+//! checked against the publisher's key in `assets/offset_feed_key.txt`.
+//!
+//! The publisher raises the sequence number with every manifest. A manifest
+//! older than the last one applied is refused, so replaying an old, validly
+//! signed feed cannot roll the offsets back.
+
+use crate::hutao_config::{ASSETS_PATH, FunctionOffsets, OFFSET_FEED_FILE, OFFSET_FEED_KEY_FILE};
+use crate::offset_checker::check_offsets;
+use crate::offset_database::{OffsetDatabase, OffsetEntry};
+use crate::pe_reader::PeFile;
+use crate::signature_scanner::Pattern;
+use serde::Deserialize;
+use std::ffi::c_void;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use windows_sys::Win32::Foundation::GetLastError;
+use windows_sys::Win32::Networking::WinHttp::*;
+use windows_sys::Win32::Security::Cryptography::*;
+
+const MAX_FEED_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct SignedManifest {
+    manifest: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    sequence: u64,
+    entries: Vec<OffsetEntry>,
+}
+
+/// The installed game, used to check feed entries that apply to it.
+pub struct Installation {
+    pub client: String,
+    pub game_version: String,
+    pub image: PeFile,
+}
+
+pub enum MergeOutcome {
+    Added,
+    Updated,
+    Unchanged,
+    /// The entry failed verification; any local entry was kept.
+    Rejected(String),
+}
+
+pub struct FeedReport {
+    /// Sequence number of the manifest.
+    pub sequence: u64,
+    /// The manifest is the one already applied; nothing was merged.
+    pub up_to_date: bool,
+    pub results: Vec<(String, MergeOutcome)>,
+}
+
+impl FeedReport {
+    pub fn changed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, o)| matches!(o, MergeOutcome::Added | MergeOutcome::Updated))
+            .count()
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, o)| matches!(o, MergeOutcome::Rejected(_)))
+            .count()
+    }
+}
+
+/// The feed URL from `assets/offset_feed.txt`.
+pub fn configured_source() -> Result<String, String> {
+    let path = Path::new(ASSETS_PATH).join(OFFSET_FEED_FILE);
+    let source = std::fs::read_to_string(&path).map_err(|_| {
+        format!(
+            "No offset feed configured, put its URL in {}",
+            path.display()
+        )
+    })?;
+    Ok(source.trim().to_string())
+}
+
+/// The publisher's key from `assets/offset_feed_key.txt`: X and Y of the P-256
+/// point as hex, optionally after the uncompressed point prefix `04`.
+pub fn configured_key() -> Result<[u8; 64], String> {
+    let path = Path::new(ASSETS_PATH).join(OFFSET_FEED_KEY_FILE);
+    let text = std::fs::read_to_string(&path).map_err(|_| {
+        format!(
+            "No offset feed key configured, put the publisher's P-256 public key in {}",
+            path.display()
+        )
+    })?;
+    parse_key(&text).ok_or_else(|| {
+        format!(
+            "Invalid offset feed key in {}, expected 64 bytes of hex",
+            path.display()
+        )
+    })
+}
+
+fn parse_key(text: &str) -> Option<[u8; 64]> {
+    let bytes = decode_hex(text)?;
+    let point = match bytes.len() {
+        65 if bytes[0] == 0x04 => &bytes[1..],
+        64 => &bytes[..],
+        _ => return None,
+    };
+    point.try_into().ok()
+}
+
+/// Fetch, verify and merge the feed. The database is written unless the
+/// manifest is the one already applied.
+pub fn update(
+    source: &str,
+    database_path: &Path,
+    installed: Option<&Installation>,
+) -> Result<FeedReport, String> {
+    let key = configured_key()?;
+    let feed = fetch(source)?;
+    let feed = verify(&feed, &key)?;
+    let manifest: Manifest =
+        serde_json::from_str(&feed).map_err(|e| format!("Invalid offset manifest: {e}"))?;
+
+    let mut database = OffsetDatabase::load_or_default(database_path)?;
+    if !is_newer(database.feed_sequence, manifest.sequence)? {
+        return Ok(FeedReport {
+            sequence: manifest.sequence,
+            up_to_date: true,
+            results: Vec::new(),
+        });
+    }
+    let report = merge(&mut database, manifest, installed);
+    database.save(database_path)?;
+    Ok(report)
+}
+
+/// Whether a manifest should be merged after the one last applied, `last`.
+/// An older one is an error: it would roll offsets back.
+fn is_newer(last: Option<u64>, sequence: u64) -> Result<bool, String> {
+    match last {
+        Some(last) if sequence < last => Err(format!(
+            "Offset feed manifest {sequence} is older than the one last applied ({last})"
+        )),
+        Some(last) => Ok(sequence > last),
+        None => Ok(true),
+    }
+}
+
+fn merge(
+    database: &mut OffsetDatabase,
+    manifest: Manifest,
+    installed: Option<&Installation>,
+) -> FeedReport {
+    let mut results = Vec::new();
+    for entry in manifest.entries {
+        let label = format!("{} {}", entry.client, entry.game_version);
+        let outcome = match validate(&entry, installed) {
+            Err(e) => MergeOutcome::Rejected(e),
+            Ok(()) => match database.find(&entry.client, &entry.game_version) {
+                None => MergeOutcome::Added,
+                Some(local) if same_entry(local, &entry) => MergeOutcome::Unchanged,
+                // A confirmed entry is only replaced by one for the same executable
+                Some(OffsetEntry {
+                    build: Some(build), ..
+                }) if entry.build.as_ref() != Some(build) => MergeOutcome::Rejected(format!(
+                    "would replace the local entry confirmed for {build}"
+                )),
+                Some(_) => MergeOutcome::Updated,
+            },
+        };
+        if matches!(outcome, MergeOutcome::Added | MergeOutcome::Updated) {
+            database.upsert(entry);
+        }
+        results.push((label, outcome));
+    }
+    database.feed_sequence = Some(manifest.sequence);
+    FeedReport {
+        sequence: manifest.sequence,
+        up_to_date: false,
+        results,
+    }
+}
+
+fn same_entry(a: &OffsetEntry, b: &OffsetEntry) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+/// Reject entries that could not be injected. Entries for the installed client
+/// and version must be made for the installed executable, if for any, and
+/// resolve completely and pass the offset checks against it.
+fn validate(entry: &OffsetEntry, installed: Option<&Installation>) -> Result<(), String> {
+    if entry.offsets.is_empty() && entry.signatures.is_empty() {
+        return Err("has no offsets".to_string());
+    }
+    entry.offsets.to_environment()?;
+    for (field, signature) in &entry.signatures {
+        if !FunctionOffsets::FIELD_NAMES.contains(&field.as_str()) {
+            return Err(format!("unknown offset field '{field}' in signatures"));
+        }
+        Pattern::parse(&signature.pattern).map_err(|e| format!("{field}: {e}"))?;
+    }
+    for (field, prologue) in &entry.prologues {
+        if !FunctionOffsets::FIELD_NAMES.contains(&field.as_str()) {
+            return Err(format!("unknown offset field '{field}' in prologues"));
+        }
+        Pattern::parse(prologue).map_err(|e| format!("{field} prologue: {e}"))?;
+    }
+
+    let Some(installed) = installed.filter(|i| {
+        entry.client == i.client
+            && (entry.game_version == i.game_version
+                || entry.build.as_ref() == Some(&i.image.fingerprint()))
+    }) else {
+        return Ok(());
+    };
+    let fingerprint = installed.image.fingerprint();
+    if let Some(build) = entry.build.as_ref().filter(|b| **b != fingerprint) {
+        return Err(format!(
+            "made for {build}, but the installed build is {fingerprint}"
+        ));
+    }
+    let resolved = entry.resolve(Some(&installed.image))?;
+    if let Some((feature, reason)) = resolved.disabled.first() {
+        return Err(format!(
+            "{} would be disabled on the installed build: {reason}",
+            feature.name()
+        ));
+    }
+    let failed: Vec<String> = check_offsets(&installed.image, &resolved.offsets)
+        .iter()
+        .filter(|r| !r.passed())
+        .map(|r| format!("{}: {}", r.field, r.problems.join("; ")))
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "fails the offset checks on the installed build ({})",
+            failed.join(", ")
+        ))
+    }
+}
+
+/// Check the envelope signature and return the signed manifest text.
+fn verify(feed: &[u8], key: &[u8; 64]) -> Result<String, String> {
+    let signed: SignedManifest =
+        serde_json::from_slice(feed).map_err(|e| format!("Invalid offset feed: {e}"))?;
+    let signature = decode_hex(&signed.signature)
+        .filter(|s| s.len() == 64)
+        .ok_or("Offset feed signature must be 64 bytes of hex")?;
+    if verify_p256(key, signed.manifest.as_bytes(), &signature)? {
+        Ok(signed.manifest)
+    } else {
+        Err("Offset feed signature does not match the configured key".to_string())
+    }
+}
+
+fn verify_p256(public_key: &[u8; 64], data: &[u8], signature: &[u8]) -> Result<bool, String> {
+    let mut blob = Vec::with_capacity(mem::size_of::<BCRYPT_ECCKEY_BLOB>() + public_key.len());
+    blob.extend_from_slice(&BCRYPT_ECDSA_PUBLIC_P256_MAGIC.to_le_bytes());
+    blob.extend_from_slice(&32u32.to_le_bytes());
+    blob.extend_from_slice(public_key);
+
+    unsafe {
+        let mut hash = [0u8; 32];
+        let status = BCryptHash(
+            BCRYPT_SHA256_ALG_HANDLE,
+            ptr::null(),
+            0,
+            data.as_ptr(),
+            data.len() as u32,
+            hash.as_mut_ptr(),
+            hash.len() as u32,
+        );
+        if status != 0 {
+            return Err(format!("BCryptHash failed: {status:#x}"));
+        }
+
+        let mut algorithm = ptr::null_mut();
+        let status = BCryptOpenAlgorithmProvider(
+            &mut algorithm,
+            BCRYPT_ECDSA_P256_ALGORITHM,
+            ptr::null(),
+            0,
+        );
+        if status != 0 {
+            return Err(format!("BCryptOpenAlgorithmProvider failed: {status:#x}"));
+        }
+        let mut key = ptr::null_mut();
+        let status = BCryptImportKeyPair(
+            algorithm,
+            ptr::null_mut(),
+            BCRYPT_ECCPUBLIC_BLOB,
+            &mut key,
+            blob.as_ptr(),
+            blob.len() as u32,
+            0,
+        );
+        if status != 0 {
+            BCryptCloseAlgorithmProvider(algorithm, 0);
+            return Err(format!("BCryptImportKeyPair failed: {status:#x}"));
+        }
+        let status = BCryptVerifySignature(
+            key,
+            ptr::null(),
+            hash.as_ptr(),
+            hash.len() as u32,
+            signature.as_ptr(),
+            signature.len() as u32,
+            0,
+        );
+        BCryptDestroyKey(key);
+        BCryptCloseAlgorithmProvider(algorithm, 0);
+        Ok(status == 0)
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Read the feed from an `http(s)://` or `file://` source.
+pub fn fetch(source: &str) -> Result<Vec<u8>, String> {
+    let data = if let Some(rest) = source.strip_prefix("file://") {
+        let path = file_url_path(rest);
+        std::fs::read(&path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?
+    } else if source.starts_with("https://") || source.starts_with("http://") {
+        http_get(source)?
+    } else {
+        return Err(format!(
+            "Unsupported offset feed source '{source}', expected http(s):// or file://"
+        ));
+    };
+    if data.len() > MAX_FEED_SIZE {
+        return Err(format!("Offset feed is larger than {MAX_FEED_SIZE} bytes"));
+    }
+    Ok(data)
+}
+
+/// `file:///C:/feed.json` -> `C:/feed.json`, `file://server/share/feed.json` -> `//server/share/feed.json`
+fn file_url_path(rest: &str) -> PathBuf {
+    let rest = percent_decode(rest);
+    match rest.strip_prefix('/') {
+        Some(local) if local.as_bytes().get(1) == Some(&b':') => PathBuf::from(local),
+        Some(_) => PathBuf::from(rest),
+        None => PathBuf::from(format!("//{rest}")),
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(Some(0)).collect()
+}
+
+/// Closes a WinHTTP handle when dropped.
+struct HttpHandle(*mut c_void);
+
+impl HttpHandle {
+    fn new(handle: *mut c_void, what: &str) -> Result<Self, String> {
+        if handle.is_null() {
+            Err(format!("{what} failed: {}", unsafe { GetLastError() }))
+        } else {
+            Ok(Self(handle))
+        }
+    }
+}
+
+impl Drop for HttpHandle {
+    fn drop(&mut self) {
+        unsafe {
+            WinHttpCloseHandle(self.0);
+        }
+    }
+}
+
+fn http_get(url: &str) -> Result<Vec<u8>, String> {
+    let url_w = wide(url);
+    unsafe {
+        let mut parts = mem::zeroed::<URL_COMPONENTS>();
+        parts.dwStructSize = mem::size_of::<URL_COMPONENTS>() as u32;
+        parts.dwHostNameLength = u32::MAX;
+        parts.dwUrlPathLength = u32::MAX;
+        parts.dwExtraInfoLength = u32::MAX;
+        if WinHttpCrackUrl(url_w.as_ptr(), 0, 0, &mut parts) == 0 {
+            return Err(format!("Invalid offset feed URL {url}: {}", GetLastError()));
+        }
+        let host = std::slice::from_raw_parts(parts.lpszHostName, parts.dwHostNameLength as usize);
+        let host = wide(&String::from_utf16_lossy(host));
+        // The query string directly follows the path
+        let object = std::slice::from_raw_parts(
+            parts.lpszUrlPath,
+            (parts.dwUrlPathLength + parts.dwExtraInfoLength) as usize,
+        );
+        let object = wide(&String::from_utf16_lossy(object));
+        let flags = if parts.nScheme == WINHTTP_INTERNET_SCHEME_HTTPS {
+            WINHTTP_FLAG_SECURE
+        } else {
+            0
+        };
+
+        let agent = wide("gi-toolkit");
+        let verb = wide("GET");
+        let session = HttpHandle::new(
+            WinHttpOpen(
+                agent.as_ptr(),
+                WINHTTP_ACCESS_TYPE_AUTOMATIC_PROXY,
+                ptr::null(),
+                ptr::null(),
+                0,
+            ),
+            "WinHttpOpen",
+        )?;
+        let connection = HttpHandle::new(
+            WinHttpConnect(session.0, host.as_ptr(), parts.nPort, 0),
+            "WinHttpConnect",
+        )?;
+        let request = HttpHandle::new(
+            WinHttpOpenRequest(
+                connection.0,
+                verb.as_ptr(),
+                object.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                flags,
+            ),
+            "WinHttpOpenRequest",
+        )?;
+        if WinHttpSendRequest(request.0, ptr::null(), 0, ptr::null(), 0, 0, 0) == 0
+            || WinHttpReceiveResponse(request.0, ptr::null_mut()) == 0
+        {
+            return Err(format!("Offset feed request failed: {}", GetLastError()));
+        }
+
+        let mut status = 0u32;
+        let mut size = mem::size_of::<u32>() as u32;
+        WinHttpQueryHeaders(
+            request.0,
+            WINHTTP_QUERY_STATUS_CODE | WINHTTP_QUERY_FLAG_NUMBER,
+            ptr::null(),
+            &mut status as *mut u32 as *mut c_void,
+            &mut size,
+            ptr::null_mut(),
+        );
+        if status != 200 {
+            return Err(format!("Offset feed returned HTTP {status}"));
+        }
+
+        let mut body = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            let mut read = 0u32;
+            if WinHttpReadData(
+                request.0,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len() as u32,
+                &mut read,
+            ) == 0
+            {
+                return Err(format!(
+                    "Reading the offset feed failed: {}",
+                    GetLastError()
+                ));
+            }
+            if read == 0 {
+                break;
+            }
+            body.extend_from_slice(&buffer[..read as usize]);
+            if body.len() > MAX_FEED_SIZE {
+                return Err(format!("Offset feed is larger than {MAX_FEED_SIZE} bytes"));
+            }
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe_reader::BuildFingerprint;
+
+    #[test]
+    fn keys_are_64_bytes_with_an_optional_prefix() {
+        let point = "11".repeat(64);
+        assert_eq!(parse_key(&point), Some([0x11; 64]));
+        assert_eq!(parse_key(&format!("04{point}\n")), Some([0x11; 64]));
+        assert_eq!(parse_key(&format!("05{point}")), None);
+        assert_eq!(parse_key(&"11".repeat(32)), None);
+        assert_eq!(parse_key("not hex"), None);
+    }
+
+    #[test]
+    fn older_manifests_are_refused() {
+        assert_eq!(is_newer(None, 1), Ok(true));
+        assert_eq!(is_newer(Some(4), 5), Ok(true));
+        assert_eq!(is_newer(Some(5), 5), Ok(false));
+        assert!(is_newer(Some(5), 4).is_err());
+    }
+
+    fn manifest(entries: &str) -> Manifest {
+        serde_json::from_str(&format!(r#"{{ "sequence": 3, "entries": [{entries}] }}"#)).unwrap()
+    }
+
+    fn installation() -> Installation {
+        Installation {
+            client: "chinese".to_string(),
+            game_version: "5.8.0".to_string(),
+            image: PeFile::parse(crate::pe_reader::tests::fixture()).unwrap(),
+        }
+    }
+
+    fn other_build() -> BuildFingerprint {
+        BuildFingerprint {
+            machine: 0x8664,
+            time_date_stamp: 0,
+            size_of_image: 0x1000,
+            content_hash: "00".to_string(),
+        }
+    }
+
+    fn outcomes(report: &FeedReport) -> Vec<&MergeOutcome> {
+        report.results.iter().map(|(_, outcome)| outcome).collect()
+    }
+
+    #[test]
+    fn merging_records_the_sequence() {
+        let mut database = OffsetDatabase::default();
+        let report = merge(
+            &mut database,
+            manifest(
+                r#"{ "client": "chinese", "game_version": "5.8.0", "offsets": { "find_string": 4096 } }"#,
+            ),
+            None,
+        );
+        assert_eq!(report.changed(), 1);
+        assert_eq!(database.feed_sequence, Some(3));
+        assert!(serde_json::from_str::<Manifest>(r#"{ "entries": [] }"#).is_err());
+    }
+
+    #[test]
+    fn entries_without_offsets_are_rejected() {
+        let mut database = OffsetDatabase::default();
+        let report = merge(
+            &mut database,
+            manifest(r#"{ "client": "chinese", "game_version": "5.8.0" }"#),
+            None,
+        );
+        assert!(matches!(outcomes(&report)[..], [MergeOutcome::Rejected(_)]));
+        assert!(database.entries.is_empty());
+    }
+
+    #[test]
+    fn entries_for_another_build_of_the_installed_version_are_rejected() {
+        let mut database = OffsetDatabase::default();
+        let mut feed = manifest(
+            r#"{ "client": "chinese", "game_version": "5.8.0", "offsets": { "find_string": 4096 } }"#,
+        );
+        feed.entries[0].build = Some(other_build());
+        let report = merge(&mut database, feed, Some(&installation()));
+        assert!(matches!(outcomes(&report)[..], [MergeOutcome::Rejected(_)]));
+        assert!(database.entries.is_empty());
+    }
+
+    #[test]
+    fn confirmed_local_entries_are_only_replaced_for_the_same_build() {
+        let local = r#"{ "client": "global", "game_version": "5.8.0", "offsets": { "find_string": 4096 } }"#;
+        let mut database = OffsetDatabase::default();
+        let mut entry = manifest(local).entries.remove(0);
+        entry.build = Some(other_build());
+        database.upsert(entry);
+
+        let update = r#"{ "client": "global", "game_version": "5.8.0", "offsets": { "find_string": 8192 } }"#;
+        let report = merge(&mut database, manifest(update), None);
+        assert!(matches!(outcomes(&report)[..], [MergeOutcome::Rejected(_)]));
+        assert_eq!(
+            database.entries[0]
+                .offsets
+                .get("find_string")
+                .map(|a| a.rva),
+            Some(4096)
+        );
+
+        let mut feed = manifest(update);
+        feed.entries[0].build = Some(other_build());
+        let report = merge(&mut database, feed, None);
+        assert!(matches!(outcomes(&report)[..], [MergeOutcome::Updated]));
+        assert_eq!(
+            database.entries[0]
+                .offsets
+                .get("find_string")
+                .map(|a| a.rva),
+            Some(8192)
+        );
+    }
+}
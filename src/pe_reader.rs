@@ -0,0 +1,357 @@
+use crate::signature_scanner::ImageRegion;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+
+#[derive(Debug, Clone)]
+pub struct SectionHeader {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_pointer: u32,
+    pub raw_size: u32,
+    pub characteristics: u32,
+}
+
+impl SectionHeader {
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+    }
+
+    pub fn contains_rva(&self, rva: u32) -> bool {
+        let size = self.virtual_size.max(self.raw_size);
+        rva >= self.virtual_address && rva - self.virtual_address < size
+    }
+}
+
+/// Identifies one exact build of the game executable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildFingerprint {
+    pub machine: u16,
+    pub time_date_stamp: u32,
+    pub size_of_image: u32,
+    /// FNV-1a 64 of the whole file, as hex.
+    pub content_hash: String,
+}
+
+impl fmt::Display for BuildFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let machine = match self.machine {
+            0x8664 => "x64",
+            0x014C => "x86",
+            0xAA64 => "ARM64",
+            _ => "unknown",
+        };
+        write!(
+            f,
+            "{machine}, timestamp {:#010x}, image size {:#x}, hash {}",
+            self.time_date_stamp, self.size_of_image, self.content_hash
+        )
+    }
+}
+
+/// A PE file read from disk. Nothing here needs a running process.
+pub struct PeFile {
+    pub machine: u16,
+    pub time_date_stamp: u32,
+    pub size_of_image: u32,
+    pub sections: Vec<SectionHeader>,
+    data: Vec<u8>,
+}
+
+impl PeFile {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let data =
+            std::fs::read(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+        Self::parse(data)
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self, String> {
+        if data.get(0..2) != Some(b"MZ") {
+            return Err("Not a PE file: missing MZ header".to_string());
+        }
+        let pe = read_u32(&data, 0x3C).ok_or("Truncated DOS header")? as usize;
+        if read_bytes(&data, pe, 4) != Some(b"PE\0\0") {
+            return Err("Not a PE file: missing PE signature".to_string());
+        }
+
+        let coff = pe + 4;
+        let machine = read_u16(&data, coff).ok_or("Truncated COFF header")?;
+        let section_count = read_u16(&data, coff + 2).ok_or("Truncated COFF header")? as usize;
+        let time_date_stamp = read_u32(&data, coff + 4).ok_or("Truncated COFF header")?;
+        let optional_size = read_u16(&data, coff + 16).ok_or("Truncated COFF header")? as usize;
+
+        // SizeOfImage sits at the same offset in PE32 and PE32+ optional headers
+        let optional = coff + 20;
+        let size_of_image = read_u32(&data, optional + 56).ok_or("Truncated optional header")?;
+
+        let table = optional + optional_size;
+        let mut sections = Vec::with_capacity(section_count);
+        for i in 0..section_count {
+            let header = table + i * 40;
+            let raw_name = read_bytes(&data, header, 8).ok_or("Truncated section table")?;
+            let name_len = raw_name.iter().position(|&b| b == 0).unwrap_or(8);
+            let field = |at: usize| read_u32(&data, header + at).ok_or("Truncated section table");
+            sections.push(SectionHeader {
+                name: String::from_utf8_lossy(&raw_name[..name_len]).into_owned(),
+                virtual_size: field(8)?,
+                virtual_address: field(12)?,
+                raw_size: field(16)?,
+                raw_pointer: field(20)?,
+                characteristics: field(36)?,
+            });
+        }
+
+        Ok(Self {
+            machine,
+            time_date_stamp,
+            size_of_image,
+            sections,
+            data,
+        })
+    }
+
+    pub fn content_hash(&self) -> u64 {
+        self.data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    pub fn fingerprint(&self) -> BuildFingerprint {
+        BuildFingerprint {
+            machine: self.machine,
+            time_date_stamp: self.time_date_stamp,
+            size_of_image: self.size_of_image,
+            content_hash: format!("{:016x}", self.content_hash()),
+        }
+    }
+
+    /// Raw bytes of a section as stored in the file, without trailing file alignment.
+    pub fn section_data(&self, section: &SectionHeader) -> &[u8] {
+        let mut size = section.raw_size as usize;
+        if section.virtual_size != 0 {
+            size = size.min(section.virtual_size as usize);
+        }
+        let start = (section.raw_pointer as usize).min(self.data.len());
+        let end = start.saturating_add(size).min(self.data.len());
+        &self.data[start..end]
+    }
+
+    pub fn section_for_rva(&self, rva: u32) -> Option<&SectionHeader> {
+        self.sections.iter().find(|s| s.contains_rva(rva))
+    }
+
+    /// File bytes mapped at `rva`, if they are stored in the file.
+    pub fn read_at_rva(&self, rva: u32, len: usize) -> Option<&[u8]> {
+        let section = self.section_for_rva(rva)?;
+        let start = (rva - section.virtual_address) as usize;
+        read_bytes(self.section_data(section), start, len)
+    }
+
+    pub fn executable_regions(&self) -> Vec<ImageRegion<'_>> {
+        self.sections
+            .iter()
+            .filter(|s| s.is_executable())
+            .map(|s| ImageRegion {
+                rva: s.virtual_address,
+                data: self.section_data(s),
+            })
+            .collect()
+    }
+}
+
+/// `len` bytes at `at`. Header fields can point anywhere, so the end is checked.
+fn read_bytes(data: &[u8], at: usize, len: usize) -> Option<&[u8]> {
+    data.get(at..at.checked_add(len)?)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    read_bytes(data, at, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    read_bytes(data, at, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const PE_OFFSET: usize = 0x80;
+    const SECTION_TABLE: usize = PE_OFFSET + 24 + 0xF0;
+    const TEXT: [u8; 5] = [0x55, 0x48, 0x89, 0xE5, 0xC3];
+
+    fn put_u16(data: &mut [u8], at: usize, value: u16) {
+        data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(data: &mut [u8], at: usize, value: u32) {
+        data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// A minimal x64 image: `.text` at 0x1000 backed by file offset 0x200, and
+    /// `.data` at 0x2000 with more virtual than raw size.
+    pub(crate) fn fixture() -> Vec<u8> {
+        let mut data = vec![0u8; 0x400];
+        data[0..2].copy_from_slice(b"MZ");
+        put_u32(&mut data, 0x3C, PE_OFFSET as u32);
+        data[PE_OFFSET..PE_OFFSET + 4].copy_from_slice(b"PE\0\0");
+        let coff = PE_OFFSET + 4;
+        put_u16(&mut data, coff, 0x8664);
+        put_u16(&mut data, coff + 2, 2);
+        put_u32(&mut data, coff + 4, 0x6543_2100);
+        put_u16(&mut data, coff + 16, 0xF0);
+        put_u32(&mut data, coff + 20 + 56, 0x3000);
+
+        let sections: [(&[u8], [u32; 4], u32); 2] = [
+            (b".text", [0x100, 0x1000, 0x200, 0x200], 0x6000_0020),
+            (b".data", [0x300, 0x2000, 0x100, 0x300], 0xC000_0040),
+        ];
+        for (i, (name, [virtual_size, virtual_address, raw_size, raw_pointer], flags)) in
+            sections.into_iter().enumerate()
+        {
+            let header = SECTION_TABLE + i * 40;
+            data[header..header + name.len()].copy_from_slice(name);
+            put_u32(&mut data, header + 8, virtual_size);
+            put_u32(&mut data, header + 12, virtual_address);
+            put_u32(&mut data, header + 16, raw_size);
+            put_u32(&mut data, header + 20, raw_pointer);
+            put_u32(&mut data, header + 36, flags);
+        }
+        data[0x200..0x200 + TEXT.len()].copy_from_slice(&TEXT);
+        data
+    }
+
+    /// An x64 image with a single `.text` section at 0x1000 holding `code`.
+    pub(crate) fn image_with_code(code: &[u8]) -> Vec<u8> {
+        let size = code.len().div_ceil(0x200) * 0x200;
+        let mut data = vec![0u8; 0x200 + size];
+        data[0..2].copy_from_slice(b"MZ");
+        put_u32(&mut data, 0x3C, PE_OFFSET as u32);
+        data[PE_OFFSET..PE_OFFSET + 4].copy_from_slice(b"PE\0\0");
+        let coff = PE_OFFSET + 4;
+        put_u16(&mut data, coff, 0x8664);
+        put_u16(&mut data, coff + 2, 1);
+        put_u16(&mut data, coff + 16, 0xF0);
+        put_u32(&mut data, coff + 20 + 56, (0x1000 + size) as u32);
+
+        data[SECTION_TABLE..SECTION_TABLE + 5].copy_from_slice(b".text");
+        put_u32(&mut data, SECTION_TABLE + 8, size as u32);
+        put_u32(&mut data, SECTION_TABLE + 12, 0x1000);
+        put_u32(&mut data, SECTION_TABLE + 16, size as u32);
+        put_u32(&mut data, SECTION_TABLE + 20, 0x200);
+        put_u32(&mut data, SECTION_TABLE + 36, 0x6000_0020);
+        data[0x200..0x200 + code.len()].copy_from_slice(code);
+        data
+    }
+
+    #[test]
+    fn reads_headers_and_sections() {
+        let image = PeFile::parse(fixture()).unwrap();
+        assert_eq!(image.machine, 0x8664);
+        assert_eq!(image.time_date_stamp, 0x6543_2100);
+        assert_eq!(image.size_of_image, 0x3000);
+        let names: Vec<&str> = image.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, [".text", ".data"]);
+        assert!(image.sections[0].is_executable());
+        assert!(!image.sections[1].is_executable());
+    }
+
+    #[test]
+    fn looks_up_sections_by_rva() {
+        let image = PeFile::parse(fixture()).unwrap();
+        let name = |rva| image.section_for_rva(rva).map(|s| s.name.as_str());
+        assert_eq!(name(0x0FFF), None);
+        assert_eq!(name(0x1000), Some(".text"));
+        // Raw data beyond the virtual size still belongs to the section
+        assert_eq!(name(0x11FF), Some(".text"));
+        assert_eq!(name(0x1200), None);
+        assert_eq!(name(0x22FF), Some(".data"));
+        assert_eq!(name(0x2300), None);
+
+        assert_eq!(image.read_at_rva(0x1000, TEXT.len()), Some(&TEXT[..]));
+        assert_eq!(image.read_at_rva(0x1001, 2), Some(&TEXT[1..3]));
+        // The section data stops at the virtual size
+        assert_eq!(image.read_at_rva(0x10FF, 2), None);
+        // Zero-filled at run time, not stored in the file
+        assert_eq!(image.read_at_rva(0x2100, 1), None);
+        assert_eq!(image.read_at_rva(0x1000, usize::MAX), None);
+
+        let regions = image.executable_regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].rva, 0x1000);
+        assert_eq!(regions[0].data.len(), 0x100);
+    }
+
+    #[test]
+    fn fingerprint_is_stable_and_covers_the_whole_file() {
+        let a = PeFile::parse(fixture()).unwrap().fingerprint();
+        let b = PeFile::parse(fixture()).unwrap().fingerprint();
+        assert_eq!(a, b);
+        // Launch history and the offset database store these, so the hash must not change
+        assert_eq!(a.content_hash, "cd60686adc2eedab");
+        assert_eq!(a.machine, 0x8664);
+        assert_eq!(a.time_date_stamp, 0x6543_2100);
+        assert_eq!(a.size_of_image, 0x3000);
+
+        let mut patched = fixture();
+        *patched.last_mut().unwrap() ^= 1;
+        let c = PeFile::parse(patched).unwrap().fingerprint();
+        assert_ne!(a.content_hash, c.content_hash);
+        assert_eq!(
+            (a.machine, a.time_date_stamp, a.size_of_image),
+            (c.machine, c.time_date_stamp, c.size_of_image)
+        );
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let data = fixture();
+        let headers_end = SECTION_TABLE + 2 * 40;
+        for len in 0..headers_end {
+            assert!(
+                PeFile::parse(data[..len].to_vec()).is_err(),
+                "parsed a file cut at {len:#x}"
+            );
+        }
+        // Section data may be missing, the headers are all that is required
+        let image = PeFile::parse(data[..headers_end].to_vec()).unwrap();
+        assert!(image.section_data(&image.sections[0]).is_empty());
+        assert_eq!(image.read_at_rva(0x1000, 1), None);
+    }
+
+    #[test]
+    fn hostile_headers_are_rejected_without_panicking() {
+        let mut data = fixture();
+        put_u32(&mut data, 0x3C, u32::MAX);
+        assert!(PeFile::parse(data).is_err());
+
+        let mut data = fixture();
+        data[PE_OFFSET] = b'N';
+        assert!(PeFile::parse(data).is_err());
+
+        let mut data = fixture();
+        put_u16(&mut data, PE_OFFSET + 4 + 2, u16::MAX);
+        assert!(PeFile::parse(data).is_err());
+
+        let mut data = fixture();
+        put_u16(&mut data, PE_OFFSET + 4 + 16, u16::MAX);
+        assert!(PeFile::parse(data).is_err());
+
+        // A section pointing past the end of the file or at the top of the address space
+        let mut data = fixture();
+        put_u32(&mut data, SECTION_TABLE + 20, u32::MAX);
+        put_u32(&mut data, SECTION_TABLE + 40 + 12, u32::MAX - 1);
+        put_u32(&mut data, SECTION_TABLE + 40 + 8, u32::MAX);
+        let image = PeFile::parse(data).unwrap();
+        assert!(image.section_data(&image.sections[0]).is_empty());
+        assert_eq!(image.read_at_rva(0x1000, 1), None);
+        assert_eq!(
+            image.section_for_rva(u32::MAX).map(|s| s.name.as_str()),
+            Some(".data")
+        );
+        assert_eq!(image.read_at_rva(u32::MAX, usize::MAX), None);
+    }
+}
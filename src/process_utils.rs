@@ -1,15 +1,18 @@
 use std::mem;
+use std::path::Path;
 use windows_sys::Win32::Foundation::*;
 use windows_sys::Win32::System::Diagnostics::ToolHelp::*;
 use windows_sys::Win32::System::Threading::*;
 
-pub fn is_process_running(proc_name: &str) -> bool {
-    for_each_process_by_name(proc_name, |_| {})
+/// Whether a game started from `exe_path` is running. Installs elsewhere do not count.
+pub fn is_game_running(exe_path: &str) -> bool {
+    for_each_process_at_path(exe_path, |_| {})
 }
 
-pub fn kill_process_by_name(proc_name: &str) -> Result<(), String> {
+/// Terminate the games started from `exe_path`, leaving other installs running.
+pub fn kill_game(exe_path: &str) -> Result<(), String> {
     let mut killed = false;
-    for_each_process_by_name(proc_name, |pid| unsafe {
+    for_each_process_at_path(exe_path, |pid| unsafe {
         let h_process = OpenProcess(PROCESS_TERMINATE, 0, pid);
         if !h_process.is_null() && h_process != INVALID_HANDLE_VALUE {
             TerminateProcess(h_process, 0);
@@ -107,3 +110,53 @@ where
         found
     }
 }
+
+/// Like `for_each_process_by_name`, for processes whose image is `exe_path`.
+/// A process whose image path cannot be queried is assumed to match.
+pub fn for_each_process_at_path<F>(exe_path: &str, mut action: F) -> bool
+where
+    F: FnMut(u32),
+{
+    let exe_path = Path::new(exe_path);
+    let Some(proc_name) = exe_path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let expected = exe_path
+        .canonicalize()
+        .unwrap_or_else(|_| exe_path.to_path_buf());
+    let mut found = false;
+    for_each_process_by_name(proc_name, |pid| {
+        let matches = process_image_path(pid).is_none_or(|image| {
+            let image = Path::new(&image)
+                .canonicalize()
+                .unwrap_or_else(|_| image.into());
+            image
+                .to_string_lossy()
+                .eq_ignore_ascii_case(&expected.to_string_lossy())
+        });
+        if matches {
+            found = true;
+            action(pid);
+        }
+    });
+    found
+}
+
+fn process_image_path(pid: u32) -> Option<String> {
+    unsafe {
+        let h_process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if h_process.is_null() {
+            return None;
+        }
+        let mut buffer = [0u16; 1024];
+        let mut len = buffer.len() as u32;
+        let ok = QueryFullProcessImageNameW(
+            h_process,
+            PROCESS_NAME_WIN32,
+            buffer.as_mut_ptr(),
+            &mut len,
+        );
+        CloseHandle(h_process);
+        (ok != 0).then(|| String::from_utf16_lossy(&buffer[..len as usize]))
+    }
+}
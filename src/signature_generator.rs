@@ -0,0 +1,335 @@
+use crate::hutao_config::{FunctionOffsets, PROLOGUE_LEN};
+use crate::offset_database::OffsetSet;
+use crate::pe_reader::PeFile;
+use crate::signature_scanner::{ImageRegion, Pattern};
+
+const MIN_SIGNATURE_LEN: usize = 16;
+const MAX_SIGNATURE_LEN: usize = 96;
+const SIGNATURE_STEP: usize = 8;
+/// Bytes compared after the signature in the new build to judge how much the
+/// function changed. The signature itself matches by definition.
+const CONTEXT_LEN: usize = 64;
+const MIN_CONTEXT_SIMILARITY: f32 = 0.75;
+
+/// Opcodes taking a ModRM byte whose RIP-relative form is common in game code.
+const MODRM_OPCODES: [u8; 15] = [
+    0x03, 0x2B, 0x33, 0x39, 0x3B, 0x80, 0x81, 0x83, 0x85, 0x89, 0x8B, 0x8D, 0xC6, 0xC7, 0xFF,
+];
+/// Two-byte SSE opcodes (after 0x0F) taking a ModRM byte.
+const SSE_MODRM_OPCODES: [u8; 10] = [0x10, 0x11, 0x28, 0x29, 0x2E, 0x2F, 0x54, 0x57, 0x59, 0x5C];
+
+/// Outcome of carrying one function over from the old build to the new one.
+pub struct FunctionDiff {
+    pub field: &'static str,
+    pub old_rva: u32,
+    /// Shortest pattern unique in the old build.
+    pub signature: Option<Pattern>,
+    /// Unique match in the new build.
+    pub new_rva: Option<u32>,
+    /// Share of non-wildcard bytes after the signature unchanged at the new location.
+    pub similarity: f32,
+    pub problem: Option<String>,
+}
+
+impl FunctionDiff {
+    pub fn confident(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+/// Wildcard operands that move between builds: rel32 branch targets and
+/// RIP-relative displacements. This is a linear heuristic, not a disassembler;
+/// a stray wildcard only makes the pattern less specific, and uniqueness is
+/// verified afterwards.
+fn mask_operands(code: &[u8]) -> Vec<Option<u8>> {
+    let mut masked: Vec<Option<u8>> = code.iter().copied().map(Some).collect();
+    let mut wildcard = |from: usize| {
+        for byte in masked.iter_mut().skip(from).take(4) {
+            *byte = None;
+        }
+    };
+    let is_rip_relative = |at: usize| code.get(at).is_some_and(|modrm| modrm & 0xC7 == 0x05);
+
+    let mut i = 0;
+    while i < code.len() {
+        let next = code.get(i + 1).copied();
+        if matches!(code[i], 0xE8 | 0xE9) {
+            wildcard(i + 1);
+            i += 5;
+        } else if code[i] == 0x0F && next.is_some_and(|op| (0x80..=0x8F).contains(&op)) {
+            wildcard(i + 2);
+            i += 6;
+        } else if code[i] == 0x0F
+            && next.is_some_and(|op| SSE_MODRM_OPCODES.contains(&op))
+            && is_rip_relative(i + 2)
+        {
+            wildcard(i + 3);
+            i += 7;
+        } else if MODRM_OPCODES.contains(&code[i]) && is_rip_relative(i + 1) {
+            wildcard(i + 2);
+            i += 6;
+        } else {
+            i += 1;
+        }
+    }
+    masked
+}
+
+/// Grow the pattern until it matches exactly once in `regions`.
+fn shortest_unique(masked: &[Option<u8>], regions: &[ImageRegion]) -> Result<Pattern, String> {
+    let shortest = MIN_SIGNATURE_LEN.min(masked.len());
+    let mut candidates: Vec<(usize, usize)> = regions
+        .iter()
+        .enumerate()
+        .flat_map(|(index, region)| {
+            Pattern::from_bytes(masked[..shortest].to_vec())
+                .find_all(region.data)
+                .into_iter()
+                .map(move |pos| (index, pos))
+        })
+        .collect();
+
+    let mut len = shortest;
+    loop {
+        let pattern = Pattern::from_bytes(masked[..len].to_vec());
+        candidates.retain(|&(index, pos)| pattern.matches_at(regions[index].data, pos));
+        if candidates.len() == 1 {
+            return Ok(pattern);
+        }
+        if len == masked.len() {
+            return Err(format!(
+                "no unique signature within {len} bytes ({} matches)",
+                candidates.len()
+            ));
+        }
+        len = (len + SIGNATURE_STEP).min(masked.len());
+    }
+}
+
+/// Share of the non-wildcard bytes of `masked` found unchanged in `code`. Bytes
+/// past the end of `code` count as changed.
+fn similarity(masked: &[Option<u8>], code: &[u8]) -> f32 {
+    let total = masked.iter().flatten().count();
+    let same = masked
+        .iter()
+        .zip(code)
+        .filter(|(old, new)| **old == Some(**new))
+        .count();
+    if total == 0 {
+        0.0
+    } else {
+        same as f32 / total as f32
+    }
+}
+
+/// How much of the code after the first `skip` bytes of the function is
+/// unchanged between the builds, or `None` if the old build has none.
+fn context_similarity(
+    old: &PeFile,
+    old_rva: u32,
+    new: &PeFile,
+    new_rva: u32,
+    skip: usize,
+) -> Option<f32> {
+    // Masked from the function start, so the operand heuristic sees whole instructions
+    let old_code = (skip + 1..=skip + CONTEXT_LEN)
+        .rev()
+        .find_map(|len| old.read_at_rva(old_rva, len))?;
+    let masked = mask_operands(old_code);
+    let new_code = (skip..=old_code.len())
+        .rev()
+        .find_map(|len| new.read_at_rva(new_rva, len))
+        .unwrap_or(&[]);
+    Some(similarity(
+        &masked[skip..],
+        new_code.get(skip..).unwrap_or(&[]),
+    ))
+}
+
+/// The first `PROLOGUE_LEN` bytes of the function at `rva`, with branch targets
+/// and RIP-relative operands wildcarded, to record as its prologue. `None` if
+/// `rva` is outside the image's sections.
+pub fn prologue_at(image: &PeFile, rva: u32) -> Option<Pattern> {
+    // Masked over a longer stretch, so an instruction crossing the end is recognised
+    let code = (1..=MAX_SIGNATURE_LEN)
+        .rev()
+        .find_map(|len| image.read_at_rva(rva, len))?;
+    let mut masked = mask_operands(code);
+    masked.truncate(PROLOGUE_LEN);
+    Some(Pattern::from_bytes(masked))
+}
+
+/// Build a signature for every known function in `old` and locate it in `new`.
+pub fn diff_builds(old: &PeFile, new: &PeFile, offsets: &OffsetSet) -> Vec<FunctionDiff> {
+    let old_regions = old.executable_regions();
+    let new_regions = new.executable_regions();
+
+    FunctionOffsets::FIELD_NAMES
+        .into_iter()
+        .map(|field| {
+            let address = offsets.get(field);
+            let old_rva = address
+                .and_then(|a| a.game_rva())
+                .and_then(|rva| u32::try_from(rva).ok())
+                .unwrap_or(0);
+            let mut diff = FunctionDiff {
+                field,
+                old_rva,
+                signature: None,
+                new_rva: None,
+                similarity: 0.0,
+                problem: None,
+            };
+            // Functions near the end of a section get a shorter window
+            let code = match old_rva {
+                0 => None,
+                _ => (MIN_SIGNATURE_LEN..=MAX_SIGNATURE_LEN)
+                    .rev()
+                    .find_map(|len| old.read_at_rva(old_rva, len)),
+            };
+            let Some(code) = code else {
+                diff.problem = Some(match address {
+                    Some(a) if a.module.is_some() => format!("in {a}, not in the game executable"),
+                    _ => "no code at the old offset".to_string(),
+                });
+                return diff;
+            };
+            let masked = mask_operands(code);
+
+            let pattern = match shortest_unique(&masked, &old_regions) {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    diff.problem = Some(format!("old build: {e}"));
+                    return diff;
+                }
+            };
+            match pattern.find_in(&new_regions).as_slice() {
+                [new_rva] => {
+                    diff.new_rva = Some(*new_rva);
+                    match context_similarity(old, old_rva, new, *new_rva, pattern.len()) {
+                        Some(similarity) => {
+                            diff.similarity = similarity;
+                            if similarity < MIN_CONTEXT_SIMILARITY {
+                                diff.problem = Some(format!(
+                                    "surrounding code changed ({:.0}% similar)",
+                                    similarity * 100.0
+                                ));
+                            }
+                        }
+                        None => {
+                            diff.problem =
+                                Some("no code after the signature to compare".to_string())
+                        }
+                    }
+                }
+                [] => diff.problem = Some("not found in new build".to_string()),
+                matches => {
+                    diff.problem = Some(format!("matched {} times in new build", matches.len()))
+                }
+            }
+            diff.signature = Some(pattern);
+            diff
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::offset_database::FunctionAddress;
+    use crate::pe_reader::tests::image_with_code;
+
+    // Bytes that repeat nowhere else in the test images
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn place(code: &mut [u8], at: u32, bytes: &[u8]) {
+        let start = at as usize - 0x1000;
+        code[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    #[test]
+    fn diff_relocates_moved_functions_and_flags_changed_ones() {
+        let moved = noise(1, 96);
+        let changed = noise(2, 96);
+        let removed = noise(3, 96);
+
+        let mut old_code = vec![0xCC; 0x400];
+        place(&mut old_code, 0x1000, &moved);
+        place(&mut old_code, 0x1100, &changed);
+        place(&mut old_code, 0x1200, &removed);
+        let mut new_code = vec![0xCC; 0x400];
+        place(&mut new_code, 0x1040, &moved);
+        // Same start, different body
+        place(&mut new_code, 0x1100, &changed[..MIN_SIGNATURE_LEN]);
+        place(
+            &mut new_code,
+            0x1100 + MIN_SIGNATURE_LEN as u32,
+            &noise(4, 80),
+        );
+
+        let old = PeFile::parse(image_with_code(&old_code)).unwrap();
+        let new = PeFile::parse(image_with_code(&new_code)).unwrap();
+        let mut offsets = OffsetSet::default();
+        offsets.set("find_string", FunctionAddress::in_game(0x1000));
+        offsets.set("open_team", FunctionAddress::in_game(0x1100));
+        offsets.set("craft_entry", FunctionAddress::in_game(0x1200));
+
+        let diffs = diff_builds(&old, &new, &offsets);
+        let diff = |field| diffs.iter().find(|d| d.field == field).unwrap();
+
+        let relocated = diff("find_string");
+        assert!(relocated.confident());
+        assert_eq!(relocated.new_rva, Some(0x1040));
+        assert_eq!(relocated.similarity, 1.0);
+
+        let flagged = diff("open_team");
+        assert_eq!(flagged.new_rva, Some(0x1100));
+        assert!(
+            flagged
+                .problem
+                .as_deref()
+                .unwrap()
+                .contains("surrounding code changed")
+        );
+
+        let missing = diff("craft_entry");
+        assert_eq!(missing.new_rva, None);
+        assert_eq!(missing.problem.as_deref(), Some("not found in new build"));
+
+        let unknown = diff("check_can_enter");
+        assert_eq!(
+            unknown.problem.as_deref(),
+            Some("no code at the old offset")
+        );
+    }
+
+    #[test]
+    fn masks_branch_targets_and_rip_relative_operands() {
+        // call rel32; mov rax, [rip+disp32]; ret
+        let code = [0xE8, 1, 2, 3, 4, 0x48, 0x8B, 0x05, 5, 6, 7, 8, 0xC3];
+        let masked = mask_operands(&code);
+        assert_eq!(masked[0], Some(0xE8));
+        assert!(masked[1..5].iter().all(Option::is_none));
+        assert_eq!(&masked[5..8], &[Some(0x48), Some(0x8B), Some(0x05)]);
+        assert!(masked[8..12].iter().all(Option::is_none));
+        assert_eq!(masked[12], Some(0xC3));
+    }
+
+    #[test]
+    fn similarity_ignores_wildcards_and_counts_missing_bytes() {
+        let masked = [Some(1), None, Some(3), Some(4)];
+        assert_eq!(similarity(&masked, &[1, 9, 3, 4]), 1.0);
+        assert_eq!(similarity(&masked, &[1, 9, 0, 4]), 2.0 / 3.0);
+        assert_eq!(similarity(&masked, &[1]), 1.0 / 3.0);
+        assert_eq!(similarity(&[None], &[1]), 0.0);
+    }
+}
@@ -0,0 +1,346 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A byte pattern such as `48 8B 05 ?? ?? ?? ?? E8`, where `??` matches any byte.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let bytes = text
+            .split_whitespace()
+            .map(|token| match token {
+                "?" | "??" => Ok(None),
+                _ => u8::from_str_radix(token, 16)
+                    .map(Some)
+                    .map_err(|_| format!("Invalid pattern byte '{token}'")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if bytes.is_empty() {
+            return Err("Empty pattern".to_string());
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn from_bytes(bytes: Vec<Option<u8>>) -> Self {
+        Self { bytes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The pattern bytes, `None` for a wildcard.
+    pub fn bytes(&self) -> &[Option<u8>] {
+        &self.bytes
+    }
+
+    pub fn matches_at(&self, data: &[u8], pos: usize) -> bool {
+        let Some(end) = pos.checked_add(self.len()) else {
+            return false;
+        };
+        data.get(pos..end).is_some_and(|window| {
+            window
+                .iter()
+                .zip(&self.bytes)
+                .all(|(b, p)| p.is_none_or(|p| p == *b))
+        })
+    }
+
+    /// All positions in `data` where the pattern matches.
+    pub fn find_all(&self, data: &[u8]) -> Vec<usize> {
+        if data.len() < self.len() {
+            return Vec::new();
+        }
+        (0..=data.len() - self.len())
+            .filter(|&pos| self.matches_at(data, pos))
+            .collect()
+    }
+
+    /// RVAs of every match across all regions.
+    pub fn find_in(&self, regions: &[ImageRegion]) -> Vec<u32> {
+        regions
+            .iter()
+            .flat_map(|region| {
+                // A match past the 32-bit address space comes from a malformed section
+                self.find_all(region.data).into_iter().filter_map(|pos| {
+                    u32::try_from(pos)
+                        .ok()
+                        .and_then(|pos| region.rva.checked_add(pos))
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tokens: Vec<String> = self
+            .bytes
+            .iter()
+            .map(|b| b.map_or("??".to_string(), |b| format!("{b:02X}")))
+            .collect();
+        write!(f, "{}", tokens.join(" "))
+    }
+}
+
+/// Follow a rel32 operand inside the match, e.g. the target of `E8 rel32`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelativeFixup {
+    /// Position of the rel32 operand, relative to the match.
+    pub operand_offset: usize,
+    /// End of the instruction the operand is relative to, relative to the match.
+    pub instruction_end: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    pub pattern: String,
+    /// Added to the match position when there is no fixup.
+    #[serde(default)]
+    pub offset: i64,
+    #[serde(default)]
+    pub fixup: Option<RelativeFixup>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanError {
+    InvalidPattern(String),
+    NotFound,
+    Ambiguous(usize),
+    OutOfRange,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanError::InvalidPattern(e) => write!(f, "{e}"),
+            ScanError::NotFound => write!(f, "signature not found"),
+            ScanError::Ambiguous(n) => write!(f, "signature matched {n} times"),
+            ScanError::OutOfRange => write!(f, "resolved address is out of range"),
+        }
+    }
+}
+
+/// Bytes of an image region as they are mapped at `rva`.
+pub struct ImageRegion<'a> {
+    pub rva: u32,
+    pub data: &'a [u8],
+}
+
+impl Signature {
+    /// Resolve to an RVA. The pattern must match exactly once across all regions.
+    pub fn resolve(&self, regions: &[ImageRegion]) -> Result<u32, ScanError> {
+        let pattern = Pattern::parse(&self.pattern).map_err(ScanError::InvalidPattern)?;
+        let mut found = None;
+        let mut count = 0;
+        for region in regions {
+            for pos in pattern.find_all(region.data) {
+                count += 1;
+                found = Some((region, pos));
+            }
+        }
+        let (region, pos) = match (count, found) {
+            (1, Some(hit)) => hit,
+            (0, _) => return Err(ScanError::NotFound),
+            (n, _) => return Err(ScanError::Ambiguous(n)),
+        };
+
+        // Fixup positions and offsets come from the database or the feed, so
+        // every sum is checked
+        let match_rva = region.rva as i64 + pos as i64;
+        let target = match &self.fixup {
+            Some(fixup) => {
+                let start = pos
+                    .checked_add(fixup.operand_offset)
+                    .ok_or(ScanError::OutOfRange)?;
+                let operand = start
+                    .checked_add(4)
+                    .and_then(|end| region.data.get(start..end))
+                    .ok_or(ScanError::OutOfRange)?;
+                let rel = i32::from_le_bytes([operand[0], operand[1], operand[2], operand[3]]);
+                i64::try_from(fixup.instruction_end)
+                    .ok()
+                    .and_then(|end| match_rva.checked_add(end))
+                    .and_then(|end| end.checked_add(rel as i64))
+            }
+            None => match_rva.checked_add(self.offset),
+        };
+        target
+            .and_then(|target| u32::try_from(target).ok())
+            .ok_or(ScanError::OutOfRange)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(pattern: &str, offset: i64, fixup: Option<RelativeFixup>) -> Signature {
+        Signature {
+            pattern: pattern.to_string(),
+            offset,
+            fixup,
+        }
+    }
+
+    #[test]
+    fn parses_bytes_and_wildcards() {
+        let pattern = Pattern::parse("48 8b ?? ? E8").unwrap();
+        assert_eq!(
+            pattern.bytes(),
+            &[Some(0x48), Some(0x8B), None, None, Some(0xE8)]
+        );
+        assert_eq!(pattern.to_string(), "48 8B ?? ?? E8");
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        assert!(Pattern::parse("").is_err());
+        assert!(Pattern::parse("   ").is_err());
+        assert!(Pattern::parse("48 GG").is_err());
+        assert!(Pattern::parse("123").is_err());
+    }
+
+    #[test]
+    fn wildcards_match_any_byte() {
+        let pattern = Pattern::parse("48 ?? 05").unwrap();
+        assert!(pattern.matches_at(&[0x48, 0x00, 0x05], 0));
+        assert!(pattern.matches_at(&[0x48, 0xFF, 0x05], 0));
+        assert!(!pattern.matches_at(&[0x48, 0xFF, 0x06], 0));
+        // Runs past the end of the data
+        assert!(!pattern.matches_at(&[0x90, 0x48, 0x00], 1));
+        assert!(!pattern.matches_at(&[0x48, 0x00, 0x05], usize::MAX));
+        assert_eq!(
+            pattern.find_all(&[0x48, 1, 5, 0x48, 2, 5, 0x48]),
+            vec![0, 3]
+        );
+        assert!(pattern.find_all(&[0x48]).is_empty());
+    }
+
+    #[test]
+    fn matches_are_reported_as_rvas_across_regions() {
+        let pattern = Pattern::parse("CC C3").unwrap();
+        let regions = [
+            ImageRegion {
+                rva: 0x1000,
+                data: &[0x90, 0xCC, 0xC3],
+            },
+            ImageRegion {
+                rva: 0x5000,
+                data: &[0xCC, 0xC3],
+            },
+        ];
+        assert_eq!(pattern.find_in(&regions), vec![0x1001, 0x5000]);
+    }
+
+    #[test]
+    fn matches_past_the_address_space_are_skipped() {
+        let pattern = Pattern::parse("CC C3").unwrap();
+        let regions = [ImageRegion {
+            rva: u32::MAX - 1,
+            data: &[0xCC, 0xC3, 0x90, 0xCC, 0xC3],
+        }];
+        assert_eq!(pattern.find_in(&regions), vec![u32::MAX - 1]);
+    }
+
+    #[test]
+    fn resolve_requires_a_unique_match() {
+        let data = [0x55, 0x48, 0x89, 0xE5, 0x55, 0x48];
+        let regions = [ImageRegion {
+            rva: 0x1000,
+            data: &data,
+        }];
+        assert_eq!(signature("48 89 E5", 0, None).resolve(&regions), Ok(0x1001));
+        assert_eq!(
+            signature("48 89 E5", -1, None).resolve(&regions),
+            Ok(0x1000)
+        );
+        assert_eq!(
+            signature("55 48", 0, None).resolve(&regions),
+            Err(ScanError::Ambiguous(2))
+        );
+        assert_eq!(
+            signature("C3", 0, None).resolve(&regions),
+            Err(ScanError::NotFound)
+        );
+        assert!(matches!(
+            signature("ZZ", 0, None).resolve(&regions),
+            Err(ScanError::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn follows_rip_relative_operands() {
+        // call rel32 at 0x2000: the target is relative to the end of the instruction
+        let data = [0x90, 0xE8, 0x0B, 0x00, 0x00, 0x00, 0xC3];
+        let regions = [ImageRegion {
+            rva: 0x2000,
+            data: &data,
+        }];
+        let fixup = || {
+            Some(RelativeFixup {
+                operand_offset: 1,
+                instruction_end: 5,
+            })
+        };
+        assert_eq!(
+            signature("E8 ?? ?? ?? ?? C3", 0, fixup()).resolve(&regions),
+            Ok(0x2001 + 5 + 0x0B)
+        );
+
+        // A negative displacement
+        let data = [0xE8, 0xF6, 0xFF, 0xFF, 0xFF];
+        let regions = [ImageRegion {
+            rva: 0x3000,
+            data: &data,
+        }];
+        assert_eq!(
+            signature(
+                "E8",
+                0,
+                fixup().map(|f| RelativeFixup {
+                    operand_offset: 1,
+                    ..f
+                })
+            )
+            .resolve(&regions),
+            Ok(0x3000 + 5 - 10)
+        );
+    }
+
+    #[test]
+    fn out_of_range_fixups_are_errors() {
+        let data = [0xE8, 0x00, 0x00, 0x00, 0x00];
+        let regions = [ImageRegion {
+            rva: 0x1000,
+            data: &data,
+        }];
+        for (operand_offset, instruction_end) in [
+            (usize::MAX, 5),
+            (usize::MAX - 2, 5),
+            (3, 5),
+            (1, usize::MAX),
+        ] {
+            let fixup = Some(RelativeFixup {
+                operand_offset,
+                instruction_end,
+            });
+            assert_eq!(
+                signature("E8", 0, fixup).resolve(&regions),
+                Err(ScanError::OutOfRange),
+                "operand_offset {operand_offset}, instruction_end {instruction_end}"
+            );
+        }
+        assert_eq!(
+            signature("E8", i64::MAX, None).resolve(&regions),
+            Err(ScanError::OutOfRange)
+        );
+        assert_eq!(
+            signature("E8", -0x2000, None).resolve(&regions),
+            Err(ScanError::OutOfRange)
+        );
+    }
+}